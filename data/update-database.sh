@@ -0,0 +1,6 @@
+#!/bin/sh
+# Refresh the bundled copy of the CHIP-8 database, see rom_db.rs
+set -e
+cd "$(dirname "$0")"
+curl -fsSL -o programs.json \
+    https://raw.githubusercontent.com/chip-8/chip-8-database/master/database/programs.json
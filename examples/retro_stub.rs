@@ -0,0 +1,261 @@
+// examples/retro_stub.rs: a bare bones libretro frontend for trying out the core
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::ffi::{c_void, CStr, CString};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use libloading::{Library, Symbol};
+use rusty_chips::libretro::{
+    GameInfo, InputDescriptor, SystemAvInfo, SystemInfo, DEVICE_JOYPAD,
+    ENVIRONMENT_SET_INPUT_DESCRIPTORS, ENVIRONMENT_SET_PIXEL_FORMAT, PIXEL_FORMAT_XRGB8888,
+};
+
+// RETRO_DEVICE_ID_JOYPAD_A
+const JOYPAD_A: u32 = 8;
+
+static FRAME: Mutex<(u32, u32, Vec<u32>)> = Mutex::new((0, 0, Vec::new()));
+static FRAMES_RUN: AtomicU64 = AtomicU64::new(0);
+static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static AUDIO_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" fn environment(cmd: u32, data: *mut c_void) -> bool {
+    match cmd {
+        ENVIRONMENT_SET_PIXEL_FORMAT => *(data as *const i32) == PIXEL_FORMAT_XRGB8888,
+        ENVIRONMENT_SET_INPUT_DESCRIPTORS => {
+            let mut d = data as *const InputDescriptor;
+            while !(*d).description.is_null() {
+                let name = CStr::from_ptr((*d).description).to_string_lossy();
+                println!("input: port {} button {} -> {}", (*d).port, (*d).id, name);
+                d = d.add(1);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video_refresh(data: *const c_void, width: u32, height: u32, pitch: usize) {
+    let mut frame = FRAME.lock().unwrap();
+    frame.0 = width;
+    frame.1 = height;
+    frame.2.clear();
+    for y in 0..height as usize {
+        let row = (data as *const u8).add(y * pitch) as *const u32;
+        frame
+            .2
+            .extend_from_slice(std::slice::from_raw_parts(row, width as usize));
+    }
+}
+
+unsafe extern "C" fn audio_sample(_left: i16, _right: i16) {}
+
+unsafe extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+    let samples = std::slice::from_raw_parts(data, frames * 2);
+    let peak = samples.iter().map(|s| s.unsigned_abs() as usize).max();
+    AUDIO_PEAK.fetch_max(peak.unwrap_or(0), Ordering::Relaxed);
+    AUDIO_FRAMES.fetch_add(frames, Ordering::Relaxed);
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+/// Joypad A held for half a second out of every second, nothing else pressed
+unsafe extern "C" fn input_state(port: u32, device: u32, _index: u32, id: u32) -> i16 {
+    let held = FRAMES_RUN.load(Ordering::Relaxed) % 60 < 30;
+    (port == 0 && device == DEVICE_JOYPAD && id == JOYPAD_A && held) as i16
+}
+
+/// The last frame as block art, lit pixels as '#'
+fn print_frame() {
+    let frame = FRAME.lock().unwrap();
+    let (width, height) = (frame.0 as usize, frame.1 as usize);
+    for y in 0..height {
+        let row: String = frame.2[y * width..(y + 1) * width]
+            .iter()
+            .map(|p| {
+                let brightness = (p >> 16 & 0xFF) + (p >> 8 & 0xFF) + (p & 0xFF);
+                if brightness > 3 * 0x7F {
+                    '#'
+                } else {
+                    ' '
+                }
+            })
+            .collect();
+        println!("|{}|", row);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: {} <core library> <rom> [frames]", args[0]);
+        exit(2);
+    }
+    let frames: u64 = args.get(3).and_then(|f| f.parse().ok()).unwrap_or(300);
+    let rom = std::fs::read(&args[2]).unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", args[2], e);
+        exit(1);
+    });
+
+    unsafe {
+        let lib = Library::new(&args[1]).unwrap_or_else(|e| {
+            eprintln!("Unable to load {}: {}", args[1], e);
+            exit(1);
+        });
+        macro_rules! sym {
+            ($name:literal, $ty:ty) => {{
+                let sym: Symbol<$ty> = lib.get($name).unwrap_or_else(|e| {
+                    eprintln!("Missing symbol: {}", e);
+                    exit(1);
+                });
+                sym
+            }};
+        }
+
+        let api_version = sym!(b"retro_api_version", extern "C" fn() -> u32);
+        let get_system_info = sym!(
+            b"retro_get_system_info",
+            unsafe extern "C" fn(*mut SystemInfo)
+        );
+        let get_av_info = sym!(
+            b"retro_get_system_av_info",
+            unsafe extern "C" fn(*mut SystemAvInfo)
+        );
+        let set_environment = sym!(
+            b"retro_set_environment",
+            unsafe extern "C" fn(unsafe extern "C" fn(u32, *mut c_void) -> bool)
+        );
+        let set_video_refresh = sym!(
+            b"retro_set_video_refresh",
+            unsafe extern "C" fn(unsafe extern "C" fn(*const c_void, u32, u32, usize))
+        );
+        let set_audio_sample = sym!(
+            b"retro_set_audio_sample",
+            unsafe extern "C" fn(unsafe extern "C" fn(i16, i16))
+        );
+        let set_audio_sample_batch = sym!(
+            b"retro_set_audio_sample_batch",
+            unsafe extern "C" fn(unsafe extern "C" fn(*const i16, usize) -> usize)
+        );
+        let set_input_poll = sym!(
+            b"retro_set_input_poll",
+            unsafe extern "C" fn(unsafe extern "C" fn())
+        );
+        let set_input_state = sym!(
+            b"retro_set_input_state",
+            unsafe extern "C" fn(unsafe extern "C" fn(u32, u32, u32, u32) -> i16)
+        );
+        let init = sym!(b"retro_init", extern "C" fn());
+        let deinit = sym!(b"retro_deinit", extern "C" fn());
+        let load_game = sym!(
+            b"retro_load_game",
+            unsafe extern "C" fn(*const GameInfo) -> bool
+        );
+        let run = sym!(b"retro_run", extern "C" fn());
+        let serialize_size = sym!(b"retro_serialize_size", extern "C" fn() -> usize);
+        let serialize = sym!(
+            b"retro_serialize",
+            unsafe extern "C" fn(*mut c_void, usize) -> bool
+        );
+        let unserialize = sym!(
+            b"retro_unserialize",
+            unsafe extern "C" fn(*const c_void, usize) -> bool
+        );
+
+        let mut info: SystemInfo = std::mem::zeroed();
+        get_system_info(&mut info);
+        println!(
+            "{} {} (API {}), extensions {}",
+            CStr::from_ptr(info.library_name).to_string_lossy(),
+            CStr::from_ptr(info.library_version).to_string_lossy(),
+            api_version(),
+            CStr::from_ptr(info.valid_extensions).to_string_lossy()
+        );
+
+        set_environment(environment);
+        set_video_refresh(video_refresh);
+        set_audio_sample(audio_sample);
+        set_audio_sample_batch(audio_sample_batch);
+        set_input_poll(input_poll);
+        set_input_state(input_state);
+        init();
+
+        let path = CString::new(args[2].as_str()).unwrap();
+        let game = GameInfo {
+            path: path.as_ptr(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: std::ptr::null(),
+        };
+        if !load_game(&game) {
+            eprintln!("The core refused {}", args[2]);
+            exit(1);
+        }
+        let mut av: SystemAvInfo = std::mem::zeroed();
+        get_av_info(&mut av);
+        println!(
+            "{}x{} up to {}x{} at {} fps, {} Hz audio",
+            av.geometry.base_width,
+            av.geometry.base_height,
+            av.geometry.max_width,
+            av.geometry.max_height,
+            av.timing.fps,
+            av.timing.sample_rate
+        );
+
+        let started = Instant::now();
+        for _ in 0..frames {
+            run();
+            FRAMES_RUN.fetch_add(1, Ordering::Relaxed);
+        }
+        let elapsed = started.elapsed();
+        print_frame();
+        println!(
+            "{} frames in {:.2?} ({:.0} fps), {} audio frames, peak {}",
+            frames,
+            elapsed,
+            frames as f64 / elapsed.as_secs_f64(),
+            AUDIO_FRAMES.load(Ordering::Relaxed),
+            AUDIO_PEAK.load(Ordering::Relaxed)
+        );
+
+        // Save, play on, load and save again: both saves should be the same
+        let size = serialize_size();
+        let mut saved = vec![0u8; size];
+        let mut restored = vec![0u8; size];
+        let ok = serialize(saved.as_mut_ptr() as *mut c_void, size)
+            && {
+                for _ in 0..60 {
+                    run();
+                }
+                unserialize(saved.as_ptr() as *const c_void, size)
+            }
+            && serialize(restored.as_mut_ptr() as *mut c_void, size);
+        let matches = ok && saved == restored;
+        println!(
+            "save state ({} bytes) round trip: {}",
+            size,
+            if matches { "ok" } else { "FAILED" }
+        );
+
+        deinit();
+        if !matches {
+            exit(1);
+        }
+    }
+}
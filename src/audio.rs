@@ -1,55 +0,0 @@
-/// audio.rs: audio timer
-/// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
-
-/// This program is free software: you can redistribute it and/or modify
-/// it under the terms of the GNU Affero General Public License as published
-/// by the Free Software Foundation, either version 3 of the License, or
-/// (at your option) any later version.
-
-/// This program is distributed in the hope that it will be useful,
-/// but WITHOUT ANY WARRANTY; without even the implied warranty of
-/// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
-/// GNU Affero General Public License for more details.
-
-/// You should have received a copy of the GNU Affero General Public License
-/// along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
-use sdl2::{AudioSubsystem, Sdl};
-
-pub struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
-}
-
-impl AudioCallback for SquareWave {
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [f32]) {
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
-    }
-}
-
-pub fn init_sdl_audio(sdl_context: &Sdl) -> (AudioSubsystem, AudioDevice<SquareWave>) {
-    let audio_sys = sdl_context.audio().unwrap();
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: None,
-    };
-    let audio_device = audio_sys
-        .open_playback(None, &desired_spec, |spec| SquareWave {
-            phase_inc: 440.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25,
-        })
-        .unwrap();
-    (audio_sys, audio_device)
-}
@@ -0,0 +1,429 @@
+/// audio/mod.rs: the buzzer and the outputs it can play through
+/// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+/// This program is free software: you can redistribute it and/or modify
+/// it under the terms of the GNU Affero General Public License as published
+/// by the Free Software Foundation, either version 3 of the License, or
+/// (at your option) any later version.
+
+/// This program is distributed in the hope that it will be useful,
+/// but WITHOUT ANY WARRANTY; without even the implied warranty of
+/// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+/// GNU Affero General Public License for more details.
+
+/// You should have received a copy of the GNU Affero General Public License
+/// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+mod null;
+#[cfg(feature = "sdl")]
+mod sdl;
+mod wav;
+
+pub use null::NullAudio;
+#[cfg(feature = "sdl")]
+pub use sdl::SdlAudio;
+pub use wav::WavAudio;
+
+// Attack and release ramp lengths, long enough to avoid clicks
+// and short enough to not soften the shortest (1/60s) beeps
+const ATTACK_SECS: f32 = 0.004;
+const RELEASE_SECS: f32 = 0.008;
+
+// Timer ticks per second, emulated time is measured in ticks
+pub(crate) const TICK_HZ: f64 = 60.0;
+// How far behind the sound timer audio plays, frame rate hiccups shorter than this are inaudible
+const BUFFER_TICKS: f64 = 3.0;
+// Past this the audio clock jumps instead of drifting back in line (startup, long stalls)
+const MAX_DRIFT_TICKS: f64 = 30.0;
+// How hard the audio clock is pulled back in line, as a fraction of playback rate
+const DRIFT_CORRECTION: f64 = 0.005;
+
+/// Buzzer pitches that can be picked, in Hz
+pub const TONE_RANGE: RangeInclusive<f32> = 50.0..=2000.0;
+
+/// A buzzer pitch in Hz, e.g. 440
+pub fn parse_tone(s: &str) -> Result<f32, String> {
+    let tone: f32 = s
+        .parse()
+        .map_err(|_| format!("'{}' is not a pitch in Hz, e.g. 440", s))?;
+    if TONE_RANGE.contains(&tone) {
+        Ok(tone)
+    } else {
+        Err(format!(
+            "{}Hz is out of range, pick {} - {}Hz",
+            tone,
+            TONE_RANGE.start(),
+            TONE_RANGE.end()
+        ))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 4] = [
+        Waveform::Square,
+        Waveform::Sine,
+        Waveform::Triangle,
+        Waveform::Noise,
+    ];
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Waveform::ALL
+            .into_iter()
+            .find(|w| w.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown waveform '{}', expected square, sine, triangle or noise",
+                    s
+                )
+            })
+    }
+}
+
+/// What the buzzer sounds like
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub waveform: Waveform,
+    // Hz
+    pub frequency: f32,
+    // 0.0 - 1.0
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Edge {
+    // emulated time, in ticks
+    at: f64,
+    on: bool,
+}
+
+#[derive(Debug, Default)]
+struct Timeline {
+    edges: VecDeque<Edge>,
+    // latest emulated time, in ticks
+    now: f64,
+}
+
+/// Buzzer on/off edges stamped with the emulated time they happen at.
+/// The sound timer writes them, the audio output plays them back.
+#[derive(Clone, Debug, Default)]
+pub struct Buzzer {
+    timeline: Arc<Mutex<Timeline>>,
+}
+
+impl Buzzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sound from tick `at` until tick `until`, replacing anything scheduled after `at`
+    pub fn schedule(&self, at: f64, until: f64) {
+        let mut timeline = self.timeline.lock().unwrap();
+        timeline.edges.retain(|e| e.at < at);
+        if until > at {
+            timeline.edges.push_back(Edge { at, on: true });
+        }
+        timeline.edges.push_back(Edge {
+            at: until.max(at),
+            on: false,
+        });
+    }
+
+    /// Whether the buzzer is sounding as of the latest tick
+    pub fn is_on(&self) -> bool {
+        let timeline = self.timeline.lock().unwrap();
+        match timeline.edges.iter().find(|e| e.at > timeline.now) {
+            // Whatever comes next undoes the current state
+            Some(next) => !next.on,
+            None => timeline.edges.back().is_some_and(|e| e.on),
+        }
+    }
+
+    /// The latest tick
+    pub fn now(&self) -> f64 {
+        self.timeline.lock().unwrap().now
+    }
+
+    /// Move emulated time forward to tick `now`
+    pub fn advance(&self, now: f64) {
+        let mut timeline = self.timeline.lock().unwrap();
+        timeline.now = now;
+        // Nobody is playing these back, don't let them pile up
+        while timeline
+            .edges
+            .front()
+            .is_some_and(|e| e.at < now - MAX_DRIFT_TICKS)
+        {
+            timeline.edges.pop_front();
+        }
+    }
+}
+
+/// The buzzer: a tone that ramps up while the gate is open and back down once it closes.
+/// The gate follows the `Buzzer` timeline, a fixed number of ticks behind the sound timer.
+pub struct ToneGenerator {
+    settings: AudioSettings,
+    sample_rate: f32,
+    phase: f32,
+    gate: bool,
+    envelope: f32,
+    noise: f32,
+    buzzer: Buzzer,
+    // emulated time of the next sample, in ticks
+    clock: f64,
+}
+
+impl ToneGenerator {
+    pub fn new(settings: AudioSettings, sample_rate: i32, buzzer: Buzzer) -> Self {
+        Self {
+            settings,
+            sample_rate: sample_rate as f32,
+            phase: 0.0,
+            gate: false,
+            envelope: 0.0,
+            noise: 0.0,
+            buzzer,
+            clock: f64::MIN,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: AudioSettings) {
+        self.settings = settings;
+    }
+
+    fn next_sample(&mut self, open: bool) -> f32 {
+        // Muting closes the gate too, so it ramps down rather than clicking
+        let target = if open && !self.settings.muted {
+            1.0
+        } else {
+            0.0
+        };
+        if self.envelope < target {
+            self.envelope = (self.envelope + 1.0 / (ATTACK_SECS * self.sample_rate)).min(1.0);
+        } else if self.envelope > target {
+            self.envelope = (self.envelope - 1.0 / (RELEASE_SECS * self.sample_rate)).max(0.0);
+        }
+
+        let p = self.phase;
+        let wave = match self.settings.waveform {
+            Waveform::Square => {
+                if p <= 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (p * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (p - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        };
+
+        self.phase += self.settings.frequency / self.sample_rate;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            // Noise is sample and hold, a new value every period keeps frequency meaningful
+            self.noise = rand::random::<f32>() * 2.0 - 1.0;
+        }
+
+        wave * self.settings.volume * self.envelope
+    }
+
+    /// Fill `out` with the next samples of the timeline
+    pub fn render(&mut self, out: &mut [f32]) {
+        let buzzer = self.buzzer.clone();
+        let mut timeline = buzzer.timeline.lock().unwrap();
+
+        let target = timeline.now - BUFFER_TICKS;
+        let mut drift = target - self.clock;
+        if drift.abs() > MAX_DRIFT_TICKS {
+            self.clock = target;
+            drift = 0.0;
+        }
+        let step = TICK_HZ / self.sample_rate as f64
+            * (1.0 + (drift / BUFFER_TICKS).clamp(-1.0, 1.0) * DRIFT_CORRECTION);
+
+        for x in out.iter_mut() {
+            // Never play past the sound timer, if it stalls so does the buzzer
+            let stalled = self.clock >= timeline.now;
+            if !stalled {
+                while let Some(edge) = timeline.edges.front() {
+                    if edge.at > self.clock {
+                        break;
+                    }
+                    self.gate = edge.on;
+                    timeline.edges.pop_front();
+                }
+                self.clock += step;
+            }
+            *x = self.next_sample(self.gate && !stalled);
+        }
+    }
+
+    /// Fill `out` with the samples from tick `at` on, in emulated time rather than in
+    /// step with a device, so the same timeline always renders the same samples
+    pub fn render_from(&mut self, at: f64, out: &mut [f32]) {
+        let buzzer = self.buzzer.clone();
+        let mut timeline = buzzer.timeline.lock().unwrap();
+        let step = TICK_HZ / self.sample_rate as f64;
+        for (n, x) in out.iter_mut().enumerate() {
+            let clock = at + n as f64 * step;
+            while let Some(edge) = timeline.edges.front() {
+                if edge.at > clock {
+                    break;
+                }
+                self.gate = edge.on;
+                timeline.edges.pop_front();
+            }
+            *x = self.next_sample(self.gate);
+        }
+    }
+}
+
+/// Somewhere for the buzzer to go
+pub trait AudioOutput {
+    /// Change what the buzzer sounds like
+    fn set_settings(&mut self, settings: AudioSettings);
+
+    /// Called once per frame, outputs that are not driven by a device callback render here
+    fn update(&mut self) {}
+}
+
+/// Which `AudioOutput` to use
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioBackend {
+    Sdl,
+    Null,
+    Wav(PathBuf),
+}
+
+impl fmt::Display for AudioBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioBackend::Sdl => write!(f, "sdl"),
+            AudioBackend::Null => write!(f, "null"),
+            AudioBackend::Wav(path) => write!(f, "wav:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for AudioBackend {
+    type Err = String;
+
+    /// sdl, null, or `wav:<path>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("wav", path)) if !path.is_empty() => Ok(AudioBackend::Wav(PathBuf::from(path))),
+            None if s.eq_ignore_ascii_case("sdl") => Ok(AudioBackend::Sdl),
+            None if s.eq_ignore_ascii_case("null") => Ok(AudioBackend::Null),
+            _ => Err(format!(
+                "unknown audio output '{}', expected sdl, null or wav:<path>",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: i32 = 44100;
+
+    fn tone(buzzer: &Buzzer) -> ToneGenerator {
+        ToneGenerator::new(AudioSettings::default(), SAMPLE_RATE, buzzer.clone())
+    }
+
+    #[test]
+    fn sound_timer_renders_exactly() {
+        let buzzer = Buzzer::new();
+        // ST=30 at tick 0
+        buzzer.schedule(0.0, 30.0);
+        buzzer.advance(1.0);
+        let mut out = vec![0.0; SAMPLE_RATE as usize];
+        tone(&buzzer).render_from(0.0, &mut out);
+
+        let release = (RELEASE_SECS * SAMPLE_RATE as f32).ceil() as usize;
+        let sounding = out.iter().position(|x| *x == 0.0).unwrap();
+        assert!((22050..=22050 + release).contains(&sounding));
+        assert!(out[sounding..].iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn silent_before_the_edge() {
+        let buzzer = Buzzer::new();
+        buzzer.schedule(10.0, 20.0);
+        buzzer.advance(30.0);
+        let mut out = vec![0.0; SAMPLE_RATE as usize / 2];
+        tone(&buzzer).render_from(0.0, &mut out);
+        // 10 ticks of silence, then the tone
+        assert!(out[..7350].iter().all(|x| *x == 0.0));
+        assert!(out[7350] != 0.0);
+    }
+
+    #[test]
+    fn tone_range() {
+        assert_eq!(parse_tone("440"), Ok(440.0));
+        assert_eq!(parse_tone("50"), Ok(50.0));
+        assert!(parse_tone("0").is_err());
+        assert!(parse_tone("-440").is_err());
+        assert!(parse_tone("20000").is_err());
+        assert!(parse_tone("loud").is_err());
+    }
+
+    #[test]
+    fn rescheduling_replaces_later_edges() {
+        let buzzer = Buzzer::new();
+        buzzer.schedule(0.0, 30.0);
+        buzzer.advance(5.0);
+        assert!(buzzer.is_on());
+        // The ROM sets ST=0 part way through
+        buzzer.schedule(5.0, 5.0);
+        buzzer.advance(6.0);
+        assert!(!buzzer.is_on());
+    }
+}
@@ -0,0 +1,24 @@
+// audio/null.rs: an audio output that goes nowhere
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use crate::audio::{AudioOutput, AudioSettings};
+
+/// For machines without a sound card, the buzzer timeline prunes itself
+#[derive(Debug, Default)]
+pub struct NullAudio;
+
+impl AudioOutput for NullAudio {
+    fn set_settings(&mut self, _settings: AudioSettings) {}
+}
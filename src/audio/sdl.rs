@@ -0,0 +1,63 @@
+// audio/sdl.rs: the buzzer played through an SDL audio device
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::{AudioSubsystem, Sdl};
+
+use crate::audio::{AudioOutput, AudioSettings, Buzzer, ToneGenerator};
+
+pub struct SdlCallback(ToneGenerator);
+
+impl AudioCallback for SdlCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.render(out);
+    }
+}
+
+pub struct SdlAudio {
+    _audio_sys: AudioSubsystem,
+    device: AudioDevice<SdlCallback>,
+}
+
+impl SdlAudio {
+    /// Open the audio device and start it; it keeps playing (silence while the gate is closed)
+    /// so starting and stopping the buzzer never cuts a wave off mid cycle
+    pub fn new(sdl_context: &Sdl, settings: AudioSettings, buzzer: Buzzer) -> Self {
+        let audio_sys = sdl_context.audio().unwrap();
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = audio_sys
+            .open_playback(None, &desired_spec, |spec| {
+                SdlCallback(ToneGenerator::new(settings, spec.freq, buzzer))
+            })
+            .unwrap();
+        device.resume();
+        Self {
+            _audio_sys: audio_sys,
+            device,
+        }
+    }
+}
+
+impl AudioOutput for SdlAudio {
+    fn set_settings(&mut self, settings: AudioSettings) {
+        self.device.lock().0.set_settings(settings);
+    }
+}
@@ -0,0 +1,147 @@
+// audio/wav.rs: the buzzer recorded to a WAV file
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::{debug, warn};
+
+use crate::audio::{AudioOutput, AudioSettings, Buzzer, ToneGenerator, TICK_HZ};
+
+const SAMPLE_RATE: u32 = 44100;
+// 16 bit mono PCM
+const BYTES_PER_SAMPLE: u32 = 2;
+const HEADER_LEN: u32 = 44;
+
+/// Renders the buzzer into a 16 bit mono WAV file, in emulated time: the file is as long
+/// as the emulator ran, however fast that was. The RIFF sizes are filled in when the
+/// output is dropped.
+pub struct WavAudio {
+    tone: ToneGenerator,
+    buzzer: Buzzer,
+    out: BufWriter<File>,
+    // Tick of the first sample
+    start: f64,
+    samples_written: u64,
+    buf: Vec<f32>,
+}
+
+impl WavAudio {
+    pub fn create(path: &Path, settings: AudioSettings, buzzer: Buzzer) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // Sizes are patched in by finish()
+        write_header(&mut out, 0)?;
+        debug!("Recording audio to {}", path.display());
+        Ok(Self {
+            start: buzzer.now(),
+            tone: ToneGenerator::new(settings, SAMPLE_RATE as i32, buzzer.clone()),
+            buzzer,
+            out,
+            samples_written: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_samples(&mut self, count: usize) -> io::Result<()> {
+        let at = self.start + self.samples_written as f64 * TICK_HZ / SAMPLE_RATE as f64;
+        self.buf.resize(count, 0.0);
+        self.tone.render_from(at, &mut self.buf);
+        for sample in self.buf.iter() {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&pcm.to_le_bytes())?;
+        }
+        self.samples_written += count as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let data_len = (self.samples_written * BYTES_PER_SAMPLE as u64).min(u32::MAX as u64);
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, data_len as u32)?;
+        self.out.flush()
+    }
+}
+
+fn write_header(out: &mut impl Write, data_len: u32) -> io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(HEADER_LEN - 8 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, mono
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&(SAMPLE_RATE * BYTES_PER_SAMPLE).to_le_bytes())?;
+    out.write_all(&(BYTES_PER_SAMPLE as u16).to_le_bytes())?;
+    out.write_all(&((BYTES_PER_SAMPLE * 8) as u16).to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
+
+impl AudioOutput for WavAudio {
+    fn set_settings(&mut self, settings: AudioSettings) {
+        self.tone.set_settings(settings);
+    }
+
+    /// Catch the file up with the sound timer
+    fn update(&mut self) {
+        let ticks = self.buzzer.now() - self.start;
+        let due = (ticks * SAMPLE_RATE as f64 / TICK_HZ) as u64;
+        if due > self.samples_written {
+            let count = (due - self.samples_written) as usize;
+            if let Err(e) = self.write_samples(count) {
+                warn!("Unable to write audio: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for WavAudio {
+    fn drop(&mut self) {
+        self.update();
+        if let Err(e) = self.finish() {
+            warn!("Unable to finish WAV file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_is_as_long_as_emulated_time() {
+        let path = std::env::temp_dir().join(format!("rusty_chips_{}.wav", std::process::id()));
+        let buzzer = Buzzer::new();
+        buzzer.schedule(0.0, 30.0);
+        let mut wav = WavAudio::create(&path, AudioSettings::default(), buzzer.clone()).unwrap();
+        for tick in 1..=60 {
+            buzzer.advance(tick as f64);
+            wav.update();
+        }
+        drop(wav);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            bytes.len(),
+            HEADER_LEN as usize + 44100 * BYTES_PER_SAMPLE as usize
+        );
+        let data_len = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        assert_eq!(data_len, 44100 * BYTES_PER_SAMPLE);
+    }
+}
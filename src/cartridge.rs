@@ -0,0 +1,306 @@
+// cartridge.rs: Octo style cartridges, programs hidden in GIF images
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::Quirks;
+use crate::octo;
+use crate::palette::{to_hex, Palette};
+use crate::recorder::FRAME_RATE;
+use crate::rom_settings::RomSettings;
+
+// Every pixel's palette index carries two bits of the payload in its low bits,
+// the high bits pick the colour that is actually shown
+const BITS_PER_PIXEL: usize = 2;
+const PIXELS_PER_BYTE: usize = 8 / BITS_PER_PIXEL;
+const WIDTH: usize = 128;
+const MIN_HEIGHT: usize = 64;
+
+/// The emulator options Octo saves with a program. Only the ones rusty_chips
+/// has a use for are named, the rest are kept so exports don't lose them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Options {
+    // Instructions per 60Hz frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tickrate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill_color2: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blend_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>,
+    // Octo's quirks, see `quirks`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shift_quirks: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_store_quirks: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump_quirks: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logic_quirks: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip_quirks: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v_blank_quirks: Option<bool>,
+    // VF set before the result of 8XY4 etc., which rusty_chips doesn't do
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vf_order_quirks: Option<bool>,
+    // Buzzer colours, screen rotation, etc.
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Options {
+    /// The speed, colours and quirks as ROM settings
+    pub fn settings(&self) -> RomSettings {
+        let (fg, bg) = (self.fill_color.clone(), self.background_color.clone());
+        RomSettings {
+            speed: self
+                .tickrate
+                .map(|ticks| format!("{}Hz", ticks as u64 * FRAME_RATE)),
+            theme: (fg.is_some() || bg.is_some()).then(|| String::from("classic")),
+            fg,
+            bg,
+            quirks: self.quirks(),
+            ..RomSettings::default()
+        }
+    }
+
+    /// The quirks Octo was set to, if it saved them
+    pub fn quirks(&self) -> Option<Quirks> {
+        let saved = [
+            self.shift_quirks,
+            self.load_store_quirks,
+            self.jump_quirks,
+            self.logic_quirks,
+            self.clip_quirks,
+            self.v_blank_quirks,
+        ];
+        if saved.iter().all(Option::is_none) {
+            return None;
+        }
+        if self.vf_order_quirks == Some(true) {
+            warn!("The cartridge asks for vfOrderQuirks, which rusty_chips doesn't have");
+        }
+        Some(Quirks {
+            shift: self.shift_quirks.unwrap_or_default(),
+            memory_leave_i_unchanged: self.load_store_quirks.unwrap_or_default(),
+            jump: self.jump_quirks.unwrap_or_default(),
+            logic: self.logic_quirks.unwrap_or_default(),
+            // Octo wraps sprites around unless told to clip them
+            wrap: !self.clip_quirks.unwrap_or_default(),
+            vblank: self.v_blank_quirks.unwrap_or_default(),
+            ..Quirks::default()
+        })
+    }
+
+    /// Options for running at `speed` seconds per instruction in `palette` with `quirks`
+    pub fn new(speed: f64, palette: &Palette, quirks: Quirks) -> Self {
+        let ticks = (1.0 / speed / FRAME_RATE as f64).round().max(1.0);
+        if quirks.memory_increment_by_x {
+            warn!("Cartridges can't hold the memoryIncrementByX quirk, leaving it out");
+        }
+        Self {
+            tickrate: Some(ticks as u32),
+            fill_color: Some(to_hex(palette.color(1))),
+            fill_color2: Some(to_hex(palette.color(2))),
+            blend_color: Some(to_hex(palette.color(3))),
+            background_color: Some(to_hex(palette.color(0))),
+            shift_quirks: Some(quirks.shift),
+            load_store_quirks: Some(quirks.memory_leave_i_unchanged),
+            jump_quirks: Some(quirks.jump),
+            logic_quirks: Some(quirks.logic),
+            clip_quirks: Some(!quirks.wrap),
+            v_blank_quirks: Some(quirks.vblank),
+            vf_order_quirks: None,
+            other: serde_json::Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    // Octo source
+    program: String,
+    #[serde(default)]
+    options: Options,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Whether `bytes` look like a GIF, and so could be a cartridge
+pub fn is_cartridge(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GIF8")
+}
+
+/// The program and options packed into a cartridge
+pub fn decode(bytes: &[u8]) -> io::Result<(Vec<u8>, Options)> {
+    let mut gif_options = gif::DecodeOptions::new();
+    gif_options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = gif_options.read_info(bytes).map_err(invalid_gif)?;
+    let mut data = Vec::new();
+    let mut byte = 0u8;
+    let mut bits = 0;
+    while let Some(frame) = decoder.read_next_frame().map_err(invalid_gif)? {
+        for index in frame.buffer.iter() {
+            byte = byte << BITS_PER_PIXEL | (index & 0b11);
+            bits += BITS_PER_PIXEL;
+            if bits == 8 {
+                data.push(byte);
+                (byte, bits) = (0, 0);
+            }
+        }
+    }
+    if data.len() < 4 {
+        return Err(invalid("not an Octo cartridge"));
+    }
+    let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let json = 4usize
+        .checked_add(len)
+        .and_then(|end| data.get(4..end))
+        .ok_or_else(|| invalid("not an Octo cartridge"))?;
+    let payload: Payload = serde_json::from_slice(json)
+        .map_err(|e| invalid(format!("not an Octo cartridge: {}", e)))?;
+    let program = octo::assemble(&payload.program)
+        .map_err(|e| invalid(format!("unable to assemble the cartridge's program: {}", e)))?;
+    Ok((program, payload.options))
+}
+
+fn invalid_gif(e: gif::DecodingError) -> io::Error {
+    invalid(format!("unreadable GIF: {}", e))
+}
+
+/// Pack `rom` and `options` into a cartridge at `path`
+pub fn write(path: &Path, rom: &[u8], options: Options, palette: &Palette) -> io::Result<()> {
+    let program = rom
+        .chunks(16)
+        .map(|line| {
+            let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02X}", b)).collect();
+            bytes.join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let payload = Payload {
+        program: format!(": main\n{}\n", program),
+        options,
+    };
+    let json = serde_json::to_vec(&payload).map_err(io::Error::other)?;
+    let mut data = (json.len() as u32).to_be_bytes().to_vec();
+    data.extend(json);
+
+    let height = (data.len() * PIXELS_PER_BYTE)
+        .div_ceil(WIDTH)
+        .max(MIN_HEIGHT);
+    let mut pixels = vec![0u8; WIDTH * height];
+    // A foreground border on the background, so the cartridge isn't a blank square
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = (i % WIDTH, i / WIDTH);
+        if x == 0 || y == 0 || x == WIDTH - 1 || y == height - 1 {
+            *pixel = 1 << BITS_PER_PIXEL;
+        }
+    }
+    let data_bits = data.iter().flat_map(|byte| {
+        (0..PIXELS_PER_BYTE)
+            .rev()
+            .map(move |n| byte >> (n * BITS_PER_PIXEL) & 0b11)
+    });
+    for (pixel, bits) in pixels.iter_mut().zip(data_bits) {
+        *pixel |= bits;
+    }
+
+    // Each colour four times over, once for every value the low bits can hold
+    let colors: Vec<u8> = (0..4)
+        .flat_map(|value| [palette.color(value); 4])
+        .flatten()
+        .collect();
+    let out = BufWriter::new(File::create(path)?);
+    let mut encoder =
+        gif::Encoder::new(out, WIDTH as u16, height as u16, &colors).map_err(io::Error::other)?;
+    let frame = gif::Frame {
+        width: WIDTH as u16,
+        height: height as u16,
+        buffer: Cow::Borrowed(&pixels),
+        ..gif::Frame::default()
+    };
+    encoder.write_frame(&frame).map_err(io::Error::other)?;
+    debug!("Wrote cartridge {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rusty_chips_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn write_then_decode() {
+        let path = temp_path("cart.gif");
+        let rom: Vec<u8> = (0..=255).cycle().take(600).collect();
+        let palette = Palette::default();
+        let quirks = Quirks {
+            vblank: true,
+            ..Quirks::default()
+        };
+        write(
+            &path,
+            &rom,
+            Options::new(1.0 / 600.0, &palette, quirks),
+            &palette,
+        )
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(is_cartridge(&bytes));
+        let (program, options) = decode(&bytes).unwrap();
+        assert_eq!(program, rom);
+        assert_eq!(options.tickrate, Some(10));
+        assert_eq!(options.fill_color, Some(to_hex(palette.color(1))));
+        assert_eq!(options.settings().speed.as_deref(), Some("600Hz"));
+        assert_eq!(options.quirks(), Some(quirks));
+    }
+
+    #[test]
+    fn octo_options() {
+        let options: Options = serde_json::from_str(
+            r#"{"tickrate": 20, "shiftQuirks": true, "clipQuirks": false, "maxSize": 3584}"#,
+        )
+        .unwrap();
+        let quirks = options.quirks().unwrap();
+        assert!(quirks.shift && quirks.wrap && !quirks.vblank);
+        assert!(options.other.contains_key("maxSize"));
+        assert_eq!(Options::default().quirks(), None);
+    }
+
+    #[test]
+    fn not_a_cartridge() {
+        assert!(!is_cartridge(b"\x00\xE0"));
+        assert!(decode(b"GIF89a").is_err());
+    }
+}
@@ -13,14 +13,16 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::vec::Vec;
 
 use log::{trace, warn};
-use tokio::sync::mpsc;
-use tokio::time::{interval, MissedTickBehavior};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, sleep_until, Instant, MissedTickBehavior};
 
-use crate::{counter, fuse, input, vram};
+use crate::{audio, counter, fuse, input, rom, vram};
 
 #[derive(Debug)]
 pub enum Chip8Message {
@@ -33,6 +35,143 @@ pub enum Chip8Message {
     ExecStart,
     // Stop exec, Load ROM, sets pc to 0x200
     LoadROM(Vec<u8>),
+    // Snapshot the machine between two instructions
+    SaveState { respond_to: oneshot::Sender<State> },
+    // Replace the machine with a snapshot
+    LoadState(Box<State>),
+    // Seconds per instruction
+    SetSpeed(f64),
+    SetQuirks(Quirks),
+}
+
+/// Behaviours CHIP-8 interpreters disagree on, named as in the CHIP-8 database.
+/// All off is how most modern interpreters behave.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place instead of copying VY shifted into it
+    pub shift: bool,
+    // FX55/FX65 move I on by X instead of X + 1
+    pub memory_increment_by_x: bool,
+    // FX55/FX65 leave I alone
+    pub memory_leave_i_unchanged: bool,
+    // Sprites wrap around the edges of the screen instead of being clipped
+    pub wrap: bool,
+    // BXNN jumps to XNN + VX instead of BNNN jumping to NNN + V0
+    pub jump: bool,
+    // DXYN waits for the start of the next 60Hz frame
+    pub vblank: bool,
+    // 8XY1/8XY2/8XY3 clear VF
+    pub logic: bool,
+}
+
+impl Quirks {
+    /// Turn a quirk on or off by its name in the CHIP-8 database, false if there is no such quirk
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        let quirk = match name {
+            "shift" => &mut self.shift,
+            "memoryIncrementByX" => &mut self.memory_increment_by_x,
+            "memoryLeaveIUnchanged" => &mut self.memory_leave_i_unchanged,
+            "wrap" => &mut self.wrap,
+            "jump" => &mut self.jump,
+            "vblank" => &mut self.vblank,
+            "logic" => &mut self.logic,
+            _ => return false,
+        };
+        *quirk = on;
+        true
+    }
+}
+
+/// Everything needed to put the machine back exactly as it was: RAM, registers,
+/// timers and the screen
+#[derive(Clone, Debug)]
+pub struct State {
+    pub memory: Box<[u8; 4096]>,
+    pub registers: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub running: bool,
+    // Part way through FX0A, waiting on a key
+    pub waiting_for_key: bool,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub screen: vram::Memory,
+}
+
+impl State {
+    const MAGIC: &'static [u8; 4] = b"RCS1";
+    /// Length of `to_bytes`, the same for every state so it can be stored in fixed slots
+    pub const SIZE: usize = 4 + 4096 + 16 + 2 + 2 + 1 + 1 + 1 + 1 + 1 + 128 * 64 / 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE);
+        out.extend_from_slice(Self::MAGIC);
+        out.extend_from_slice(&self.memory[..]);
+        out.extend_from_slice(&self.registers);
+        out.extend_from_slice(&self.i.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.push(self.sp);
+        out.push(self.running as u8 | (self.waiting_for_key as u8) << 1);
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        out.push(match self.screen.screen_size() {
+            vram::ScreenSize::S => 0,
+            vram::ScreenSize::L => 1,
+        });
+        // One bit per pixel, padded out to the large screen
+        let (width, height) = self.screen.size();
+        let mut bits = [0u8; 128 * 64 / 8];
+        for y in 0..height {
+            for x in 0..width {
+                if self.screen[(x, y)] {
+                    let n = y * width + x;
+                    bits[n / 8] |= 1 << (n % 8);
+                }
+            }
+        }
+        out.extend_from_slice(&bits);
+        out
+    }
+
+    /// None unless `bytes` came from `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE || &bytes[..4] != Self::MAGIC {
+            return None;
+        }
+        let mut memory = Box::new([0u8; 4096]);
+        memory.copy_from_slice(&bytes[4..4100]);
+        let mut registers = [0u8; 16];
+        registers.copy_from_slice(&bytes[4100..4116]);
+        let rest = &bytes[4116..];
+        let size = match rest[8] {
+            0 => vram::ScreenSize::S,
+            1 => vram::ScreenSize::L,
+            _ => return None,
+        };
+        let mut screen = vram::Memory::blank(size);
+        let (width, height) = screen.size();
+        let bits = &rest[9..];
+        for y in 0..height {
+            for x in 0..width {
+                let n = y * width + x;
+                screen[(x, y)] = bits[n / 8] & (1 << (n % 8)) != 0;
+            }
+        }
+        Some(Self {
+            memory,
+            registers,
+            i: u16::from_le_bytes([rest[0], rest[1]]),
+            pc: u16::from_le_bytes([rest[2], rest[3]]),
+            sp: rest[4],
+            running: rest[5] & 1 != 0,
+            waiting_for_key: rest[5] & 2 != 0,
+            delay_timer: rest[6],
+            sound_timer: rest[7],
+            screen,
+        })
+    }
 }
 
 #[allow(non_snake_case)]
@@ -69,11 +208,21 @@ pub struct Chip8 {
     // Keypad buttons, pressed or not
     input: input::InputHandle,
 
+    // FX0A: waiting on a key released after this input event
+    key_wait: Option<u64>,
+
     // Video RAM, for SDL or other library to read from in a thread safe manner
     video: vram::VRAMHandle,
 
     // mbox for execution control
     exec: mpsc::Receiver<Chip8Message>,
+
+    // Set by SetSpeed until run_chip8 picks it up
+    new_speed: Option<f64>,
+
+    quirks: Quirks,
+    // What 60Hz frames are counted from, for the vblank quirk
+    started: Instant,
 }
 
 impl Chip8 {
@@ -97,14 +246,19 @@ impl Chip8 {
             delay_timer,
             sound_timer,
             input,
+            key_wait: None,
             video,
             exec,
+            new_speed: None,
+            quirks: Quirks::default(),
+            started: Instant::now(),
         }
     }
 
     pub fn load_rom(&mut self, rom: &Vec<u8>) {
         self.load_bytes_at(rom, 0x200);
         self.reset_pc();
+        self.key_wait = None;
     }
 
     /// MAGIC NUMBER 3584:  0x1000 (4096/ram size) - 0x200 (self.pc / start of exec)
@@ -122,7 +276,7 @@ impl Chip8 {
         self.pc = 0x200;
     }
 
-    pub fn handle_message(&mut self, msg: Chip8Message) {
+    pub async fn handle_message(&mut self, msg: Chip8Message) {
         match msg {
             Chip8Message::ExecPause => {
                 self.running = false;
@@ -138,9 +292,48 @@ impl Chip8 {
             Chip8Message::LoadROM(rom) => {
                 self.load_rom(&rom);
             }
+            Chip8Message::SaveState { respond_to } => {
+                let _ = respond_to.send(self.save_state().await);
+            }
+            Chip8Message::LoadState(state) => self.load_state(*state).await,
+            Chip8Message::SetSpeed(secs) => self.new_speed = Some(secs),
+            Chip8Message::SetQuirks(quirks) => self.quirks = quirks,
+        }
+    }
+
+    async fn save_state(&self) -> State {
+        State {
+            memory: Box::new(self.memory),
+            registers: self.vS,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            running: self.running,
+            waiting_for_key: self.key_wait.is_some(),
+            delay_timer: self.delay_timer.get().await,
+            sound_timer: self.sound_timer.get().await,
+            screen: self.video.screen().await,
         }
     }
 
+    async fn load_state(&mut self, state: State) {
+        self.memory = *state.memory;
+        self.vS = state.registers;
+        self.i = state.i;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.running = state.running;
+        // Key events from before the state was saved are gone, wait on the next ones
+        self.key_wait = if state.waiting_for_key {
+            Some(self.input.last_seq().await)
+        } else {
+            None
+        };
+        self.delay_timer.set(state.delay_timer).await;
+        self.sound_timer.set(state.sound_timer).await;
+        self.video.load_screen(state.screen).await;
+    }
+
     pub async fn cycle(&mut self) {
         if self.running {
             // fetch
@@ -223,9 +416,16 @@ impl Chip8 {
                     let ending = 0x000F & opcode;
                     match ending {
                         0x0 => self.vS[x] = self.vS[y],
-                        0x1 => self.vS[x] |= self.vS[y],
-                        0x2 => self.vS[x] &= self.vS[y],
-                        0x3 => self.vS[x] ^= self.vS[y],
+                        0x1..=0x3 => {
+                            match ending {
+                                0x1 => self.vS[x] |= self.vS[y],
+                                0x2 => self.vS[x] &= self.vS[y],
+                                _ => self.vS[x] ^= self.vS[y],
+                            }
+                            if self.quirks.logic {
+                                self.vS[15] = 0;
+                            }
+                        }
                         0x4 => {
                             let x_val: u16 = self.vS[x] as u16;
                             let y_val: u16 = self.vS[y] as u16;
@@ -247,7 +447,7 @@ impl Chip8 {
                             self.vS[x] = self.vS[x].wrapping_sub(self.vS[y]);
                         }
                         0x6 => {
-                            let y_val = self.vS[y];
+                            let y_val = self.vS[if self.quirks.shift { x } else { y }];
                             let flag = 0b00000001 & y_val;
                             self.vS[15] = flag;
                             self.vS[x] = y_val >> 1;
@@ -263,7 +463,7 @@ impl Chip8 {
                             self.vS[x] = y_val.wrapping_sub(x_val);
                         }
                         0xE => {
-                            let y_val = self.vS[y];
+                            let y_val = self.vS[if self.quirks.shift { x } else { y }];
                             let msb = (0b10000000 & y_val).rotate_left(1);
                             self.vS[15] = msb;
                             self.vS[x] = y_val << 1;
@@ -290,7 +490,12 @@ impl Chip8 {
                     self.i = opcode & 0x0FFF;
                 }
                 0xB000..=0xBFFF => {
-                    self.pc = (0xFFF & opcode) + (self.vS[0] as u16);
+                    let v = if self.quirks.jump {
+                        (0x0F00 & opcode) >> 8
+                    } else {
+                        0
+                    };
+                    self.pc = (0xFFF & opcode) + (self.vS[v as usize] as u16);
                 }
                 0xC000..=0xCFFF => {
                     let x = (((0x0F00 & opcode) >> 8) as u8) as usize;
@@ -346,7 +551,29 @@ impl Chip8 {
                             self.vS[x] = self.delay_timer.get().await;
                         }
                         0xA => {
-                            todo!("Waiting for input");
+                            // Wait for a key to be pressed and released (as the COSMAC VIP did),
+                            // by re-running this instruction until it has been
+                            match self.key_wait {
+                                None => {
+                                    self.key_wait = Some(self.input.last_seq().await);
+                                    self.pc -= 2;
+                                }
+                                Some(since) => {
+                                    let events = self.input.events_since(since).await;
+                                    match input::first_release(&events) {
+                                        Some(e) => {
+                                            trace!(
+                                                "FX0A: got {:X} after {:?}",
+                                                e.key,
+                                                e.at.elapsed()
+                                            );
+                                            self.vS[x] = e.key;
+                                            self.key_wait = None;
+                                        }
+                                        None => self.pc -= 2,
+                                    }
+                                }
+                            }
                         }
                         0x15 => {
                             self.delay_timer.set(self.vS[x]).await;
@@ -370,13 +597,14 @@ impl Chip8 {
                                 let ix = (self.i + (idx as u16)) as usize;
                                 self.memory[ix] = self.vS[idx];
                             }
+                            self.move_i_after_memory(x);
                         }
                         0x65 => {
                             for idx in 0..=x {
                                 let ix = (self.i + (idx as u16)) as usize;
                                 self.vS[idx] = self.memory[ix];
                             }
-                            self.i += x as u16 + 1;
+                            self.move_i_after_memory(x);
                         }
                         _ => unknown_opcode(opcode),
                     }
@@ -407,46 +635,33 @@ impl Chip8 {
         self.sp -= 2;
     }
 
+    /// FX55/FX65 are done with registers V0 to VX
+    fn move_i_after_memory(&mut self, x: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        self.i += x as u16;
+        if !self.quirks.memory_increment_by_x {
+            self.i += 1;
+        }
+    }
+
     async fn draw(&mut self, vx: usize, vy: usize, bytes: &[u8]) {
         if !self.running {
             return;
         }
-        let (sx, sy) = self.video.get_screen_size();
-        let tx = vx % sx;
-        let ty = vy % sy;
-        let mut collision: u8 = 0;
-
-        let masks: [u8; 8] = [
-            0b10000000, 0b01000000, 0b00100000, 0b00010000, 0b00001000, 0b00000100, 0b00000010,
-            0b00000001,
-        ];
-
-        for (row, b) in bytes.iter().enumerate() {
-            let y = (row + ty) % sy;
-            if y < sy {
-                for (col, mask) in masks.iter().enumerate() {
-                    let x = (tx + col) % sx;
-                    let bmask = mask & b;
-                    let cur_value = self.video.get_pixel(x, y).await;
-                    if bmask > 0 {
-                        if cur_value {
-                            self.video.set_pixel(x, y, false).await;
-                            collision = 1;
-                        } else {
-                            self.video.set_pixel(x, y, true).await;
-                        }
-                    }
-                    if (x + 1) == sx {
-                        break;
-                    }
-                }
-                if (y + 1) == sy {
-                    break;
-                }
-            }
+        if self.quirks.vblank {
+            sleep_until(self.next_frame()).await;
         }
+        let collision = self.video.draw(vx, vy, bytes, self.quirks.wrap).await;
+        self.vS[15] = collision as u8;
+    }
 
-        self.vS[15] = collision;
+    /// When the next 60Hz frame starts
+    fn next_frame(&self) -> Instant {
+        let frame = Duration::from_nanos(1_000_000_000 / 60);
+        let frames = self.started.elapsed().as_nanos() / frame.as_nanos() + 1;
+        self.started + frame * frames as u32
     }
 }
 
@@ -454,11 +669,14 @@ fn unknown_opcode(opcode: u16) {
     warn!("Unknown opcode: 0x{:0<4X}", opcode);
 }
 
+#[derive(Clone, Debug)]
 pub struct Chip8Handle {
+    pub buzzer: audio::Buzzer,
     pub sound_timer: counter::CounterHandle,
     pub delay_timer: counter::CounterHandle,
-    pub send: mpsc::Sender<Chip8Message>,
-    pub running: bool,
+    send: mpsc::Sender<Chip8Message>,
+    // SHA-1 of the loaded ROM, empty until one is loaded
+    rom_sha1: Arc<RwLock<String>>,
 }
 
 impl Chip8Handle {
@@ -469,9 +687,11 @@ impl Chip8Handle {
         video: vram::VRAMHandle,
         fuse: fuse::FuseHandle,
     ) -> Self {
-        let sound_timer = counter::CounterHandle::new();
+        let buzzer = audio::Buzzer::new();
+        let sound_timer = counter::CounterHandle::with_buzzer(buzzer.clone());
         let delay_timer = counter::CounterHandle::new();
         let (send, recv) = mpsc::channel(10);
+        let rom_sha1 = rom.as_deref().map(rom::sha1).unwrap_or_default();
         let c8 = init_chip8(
             &rom,
             input,
@@ -483,18 +703,30 @@ impl Chip8Handle {
         tokio::spawn(async move { run_chip8(freq, fuse, c8).await });
 
         Self {
+            buzzer,
             sound_timer,
             delay_timer,
             send,
-            running: false,
+            rom_sha1: Arc::new(RwLock::new(rom_sha1)),
         }
     }
 
     pub async fn load_rom(&self, rom: Vec<u8>) {
+        *self.rom_sha1.write().unwrap() = rom::sha1(&rom);
         let msg = Chip8Message::LoadROM(rom);
         self.send.send(msg).await.unwrap();
     }
 
+    /// SHA-1 of the ROM last loaded, see `rom::sha1`
+    pub fn rom_sha1(&self) -> String {
+        self.rom_sha1.read().unwrap().clone()
+    }
+
+    /// Change how many seconds each instruction takes
+    pub async fn set_speed(&self, secs: f64) {
+        self.send.send(Chip8Message::SetSpeed(secs)).await.unwrap();
+    }
+
     pub async fn toggle_exec(&self) {
         self.send.send(Chip8Message::ExecToggle).await.unwrap();
     }
@@ -506,6 +738,25 @@ impl Chip8Handle {
     pub async fn unpause(&self) {
         self.send.send(Chip8Message::ExecStart).await.unwrap();
     }
+
+    pub async fn save_state(&self) -> State {
+        let (send, recv) = oneshot::channel();
+        let msg = Chip8Message::SaveState { respond_to: send };
+        self.send.send(msg).await.unwrap();
+        recv.await.unwrap()
+    }
+
+    pub async fn load_state(&self, state: State) {
+        let msg = Chip8Message::LoadState(Box::new(state));
+        self.send.send(msg).await.unwrap();
+    }
+
+    pub async fn set_quirks(&self, quirks: Quirks) {
+        self.send
+            .send(Chip8Message::SetQuirks(quirks))
+            .await
+            .unwrap();
+    }
 }
 
 pub fn init_chip8(
@@ -556,12 +807,143 @@ async fn run_chip8(frequency: f64, fuse: fuse::FuseHandle, mut c8: Chip8) {
     let mut ival = interval(Duration::from_secs_f64(frequency));
     ival.set_missed_tick_behavior(MissedTickBehavior::Skip);
     while fuse.alive() {
-        ival.tick().await;
-        c8.cycle().await;
-
-        if let Ok(msg) = c8.exec.try_recv() {
-            c8.handle_message(msg)
+        // Messages are handled between instructions as they arrive, without waiting on a tick
+        tokio::select! {
+            _ = ival.tick() => c8.cycle().await,
+            Some(msg) = c8.exec.recv() => c8.handle_message(msg).await,
+        }
+        if let Some(secs) = c8.new_speed.take() {
+            ival = interval(Duration::from_secs_f64(secs));
+            ival.set_missed_tick_behavior(MissedTickBehavior::Skip);
         }
     }
     trace!("Exiting Chip8 Task");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(size: vram::ScreenSize) -> State {
+        let mut memory = Box::new([0u8; 4096]);
+        for (n, byte) in memory.iter_mut().enumerate() {
+            *byte = (n * 7) as u8;
+        }
+        let mut screen = vram::Memory::blank(size);
+        let (width, height) = screen.size();
+        for y in 0..height {
+            for x in 0..width {
+                screen[(x, y)] = (x * 3 + y) % 5 == 0;
+            }
+        }
+        State {
+            memory,
+            registers: [0xA5; 16],
+            i: 0x345,
+            pc: 0x2FE,
+            sp: 0x12,
+            running: true,
+            waiting_for_key: true,
+            delay_timer: 42,
+            sound_timer: 7,
+            screen,
+        }
+    }
+
+    #[test]
+    fn state_bytes_round_trip() {
+        for size in [vram::ScreenSize::S, vram::ScreenSize::L] {
+            let bytes = state(size).to_bytes();
+            assert_eq!(bytes.len(), State::SIZE);
+            let restored = State::from_bytes(&bytes).unwrap();
+            assert_eq!(restored.to_bytes(), bytes);
+            assert_eq!(restored.screen.screen_size(), size);
+            assert!(restored.waiting_for_key);
+        }
+    }
+
+    /// Registers and I after running `rom` for a while with `quirks`
+    async fn run(rom: &[u8], quirks: Quirks) -> State {
+        let emu = crate::Emulator::start(1.0 / 1000.0, Some(rom.to_vec()));
+        emu.chip8.set_quirks(quirks).await;
+        emu.chip8.unpause().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let state = emu.chip8.save_state().await;
+        emu.fuse.blow();
+        state
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quirks_change_instructions() {
+        let rom = [
+            0x60, 0x05, // V0 = 5
+            0x61, 0x0C, // V1 = 0xC
+            0x80, 0x16, // V0 = V1 >> 1, or V0 >> 1 with the shift quirk
+            0xA3, 0x00, // I = 0x300
+            0xF1, 0x55, // Save V0 and V1 at I
+            0x82, 0x01, // V2 |= V0, clears VF with the logic quirk
+            0x12, 0x0C, // Loop
+        ];
+        let state = run(&rom, Quirks::default()).await;
+        assert_eq!(state.registers[0], 6);
+        assert_eq!(state.memory[0x300..0x302], [6, 0xC]);
+        assert_eq!(state.i, 0x302);
+        assert_eq!(state.registers[15], 0);
+
+        let quirks = Quirks {
+            shift: true,
+            memory_leave_i_unchanged: true,
+            ..Quirks::default()
+        };
+        let state = run(&rom, quirks).await;
+        assert_eq!(state.registers[0], 2);
+        assert_eq!(state.i, 0x300);
+        // Shifted out of V0
+        assert_eq!(state.registers[15], 1);
+        let quirks = Quirks {
+            shift: true,
+            logic: true,
+            ..Quirks::default()
+        };
+        assert_eq!(run(&rom, quirks).await.registers[15], 0);
+        let quirks = Quirks {
+            memory_increment_by_x: true,
+            ..Quirks::default()
+        };
+        assert_eq!(run(&rom, quirks).await.i, 0x301);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vblank_quirk_draws_once_a_frame() {
+        let rom = [
+            0xA0, 0x50, // I = font 0
+            0xD0, 0x15, // Draw it
+            0x71, 0x01, // V1 += 1
+            0x12, 0x02, // Loop back to the draw
+        ];
+        // 50 instructions in 50ms, three frames
+        assert!(run(&rom, Quirks::default()).await.registers[1] > 10);
+        let quirks = Quirks {
+            vblank: true,
+            ..Quirks::default()
+        };
+        assert!(run(&rom, quirks).await.registers[1] <= 3);
+    }
+
+    #[test]
+    fn quirks_by_database_name() {
+        let mut quirks = Quirks::default();
+        assert!(quirks.set("memoryIncrementByX", true));
+        assert!(quirks.memory_increment_by_x);
+        assert!(!quirks.set("sparkle", true));
+    }
+
+    #[test]
+    fn state_bytes_are_checked() {
+        let bytes = state(vram::ScreenSize::S).to_bytes();
+        assert!(State::from_bytes(&bytes[..State::SIZE - 1]).is_none());
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(State::from_bytes(&wrong_magic).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+// config.rs: settings file, the defaults command line options override
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioBackend, AudioSettings, Waveform, TONE_RANGE};
+use crate::display::{DisplaySettings, RenderMode, ScaleMode};
+use crate::palette::Palette;
+use crate::ui::FrontendKind;
+use crate::util;
+
+const CONFIG_FILE: &str = "config.toml";
+const WINDOW_SIZE: (u32, u32) = (1280, 720);
+
+/// Everything in config.toml. Values are spelled the same as on the command line,
+/// anything missing from the file takes its default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub speed: String,
+    pub waveform: Waveform,
+    pub tone: f32,
+    pub volume: f32,
+    pub mute: bool,
+    pub audio: String,
+    pub theme: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bg: Option<String>,
+    pub render: RenderMode,
+    pub phosphor_frames: u8,
+    pub window_size: String,
+    pub fullscreen: bool,
+    pub scale: ScaleMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshot_dir: Option<PathBuf>,
+    // Unset picks the best frontend the build has
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frontend: Option<String>,
+    // Where the Load ROM window starts out, the working directory if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rom_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let audio = AudioSettings::default();
+        let display = DisplaySettings::default();
+        Self {
+            speed: String::from(util::DEFAULT_SPEED),
+            waveform: audio.waveform,
+            tone: audio.frequency,
+            volume: audio.volume,
+            mute: audio.muted,
+            audio: AudioBackend::Sdl.to_string(),
+            theme: Palette::default().name,
+            fg: None,
+            bg: None,
+            render: display.mode,
+            phosphor_frames: display.phosphor_frames,
+            window_size: format!("{}x{}", WINDOW_SIZE.0, WINDOW_SIZE.1),
+            fullscreen: false,
+            scale: ScaleMode::default(),
+            screenshot_dir: None,
+            frontend: None,
+            rom_dir: None,
+        }
+    }
+}
+
+/// `value` parsed, or the default with a warning if it doesn't parse
+fn parsed<T>(
+    setting: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+    default: impl FnOnce() -> T,
+) -> T {
+    parse(value).unwrap_or_else(|e| {
+        warn!("Ignoring {} in {}: {}", setting, CONFIG_FILE, e);
+        default()
+    })
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        util::config_dir().map(|d| d.join(CONFIG_FILE))
+    }
+
+    /// The user's config file, written out with the defaults if there isn't one yet
+    pub fn load() -> Self {
+        if Self::path().is_some_and(|path| !path.exists()) {
+            let config = Self::default();
+            config.save();
+            return config;
+        }
+        util::load_toml(CONFIG_FILE)
+    }
+
+    pub fn save(&self) {
+        util::save_toml(CONFIG_FILE, self);
+    }
+
+    /// Seconds per instruction
+    pub fn cpu_speed(&self) -> f64 {
+        parsed("speed", &self.speed, util::parse_speed, || {
+            util::hz_to_secs(util::DEFAULT_SPEED)
+        })
+    }
+
+    pub fn audio_settings(&self) -> AudioSettings {
+        AudioSettings {
+            waveform: self.waveform,
+            frequency: self.tone.clamp(*TONE_RANGE.start(), *TONE_RANGE.end()),
+            volume: self.volume.clamp(0.0, 1.0),
+            muted: self.mute,
+        }
+    }
+
+    pub fn set_audio_settings(&mut self, audio: AudioSettings) {
+        self.waveform = audio.waveform;
+        self.tone = audio.frequency;
+        self.volume = audio.volume;
+        self.mute = audio.muted;
+    }
+
+    pub fn audio_backend(&self) -> AudioBackend {
+        parsed("audio", &self.audio, AudioBackend::from_str, || {
+            AudioBackend::Sdl
+        })
+    }
+
+    /// The theme with the colour overrides applied
+    pub fn palette(&self) -> Palette {
+        let from_settings =
+            |theme: &str| Palette::from_settings(theme, self.fg.as_deref(), self.bg.as_deref());
+        parsed("theme", &self.theme, from_settings, Palette::default)
+    }
+
+    pub fn set_palette(&mut self, palette: &Palette) {
+        (self.theme, self.fg, self.bg) = palette.to_settings();
+    }
+
+    pub fn display(&self) -> DisplaySettings {
+        DisplaySettings {
+            mode: self.render,
+            phosphor_frames: self.phosphor_frames.max(1),
+        }
+    }
+
+    pub fn set_display(&mut self, display: DisplaySettings) {
+        self.render = display.mode;
+        self.phosphor_frames = display.phosphor_frames;
+    }
+
+    pub fn window_size(&self) -> (u32, u32) {
+        parsed("window_size", &self.window_size, util::parse_size, || {
+            WINDOW_SIZE
+        })
+    }
+
+    pub fn frontend(&self) -> FrontendKind {
+        match &self.frontend {
+            Some(frontend) => parsed(
+                "frontend",
+                frontend,
+                FrontendKind::from_str,
+                FrontendKind::default,
+            ),
+            None => FrontendKind::default(),
+        }
+    }
+
+    /// Screenshots and recordings go here unless config.toml or --screenshot-dir say otherwise
+    pub fn default_screenshot_dir() -> PathBuf {
+        util::config_dir()
+            .map(|dir| dir.join("screenshots"))
+            .unwrap_or_else(|| PathBuf::from("screenshots"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let config: Config = toml::from_str(
+            r##"
+speed = "1.76MHz"
+theme = "amber"
+fg = "#FFFFFF"
+window_size = "800x600"
+scale = "fit"
+frontend = "headless"
+"##,
+        )
+        .unwrap();
+        assert_eq!(config.cpu_speed(), util::parse_speed("1.76MHz").unwrap());
+        assert_eq!(config.palette().name, "amber");
+        assert_eq!(config.palette().foreground(), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(config.window_size(), (800, 600));
+        assert_eq!(config.scale, ScaleMode::Fit);
+        assert_eq!(config.frontend(), FrontendKind::Headless);
+        assert_eq!(config.audio_settings(), AudioSettings::default());
+        assert!(!config.fullscreen);
+    }
+
+    #[test]
+    fn bad_values_fall_back() {
+        let config: Config = toml::from_str(
+            r##"
+speed = "fast"
+theme = "purple"
+volume = 3.0
+tone = 0.0
+window_size = "big"
+audio = "speaker"
+frontend = "vr"
+"##,
+        )
+        .unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.cpu_speed(), defaults.cpu_speed());
+        assert_eq!(config.palette(), Palette::default());
+        assert_eq!(config.audio_settings().volume, 1.0);
+        assert_eq!(config.audio_settings().frequency, *TONE_RANGE.start());
+        assert_eq!(config.window_size(), WINDOW_SIZE);
+        assert_eq!(config.audio_backend(), AudioBackend::Sdl);
+        assert_eq!(config.frontend(), FrontendKind::default());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = Config::default();
+        config.set_palette(&Palette::from_settings("green", None, Some("#000000")).unwrap());
+        config.set_display(DisplaySettings {
+            mode: RenderMode::Phosphor,
+            phosphor_frames: 4,
+        });
+        config.rom_dir = Some(PathBuf::from("/roms"));
+        let saved = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&saved).unwrap(), config);
+        assert!(toml::from_str::<Config>("render = \"sparkly\"").is_err());
+    }
+}
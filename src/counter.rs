@@ -14,7 +14,9 @@
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use tokio::sync::{mpsc, oneshot};
-use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use crate::audio::Buzzer;
 
 #[derive(Debug)]
 pub enum CounterMessage {
@@ -26,11 +28,22 @@ pub enum CounterMessage {
 pub struct Counter {
     recv: mpsc::Receiver<CounterMessage>,
     value: u8,
+    // Ticks counted so far, this is the emulated clock the buzzer runs on
+    ticks: u64,
+    last_tick: Instant,
+    // Sound timers tell the buzzer when the count starts and when it will reach 0
+    buzzer: Option<Buzzer>,
 }
 
 impl Counter {
-    fn new(recv: mpsc::Receiver<CounterMessage>) -> Self {
-        Counter { recv, value: 0 }
+    fn new(recv: mpsc::Receiver<CounterMessage>, buzzer: Option<Buzzer>) -> Self {
+        Counter {
+            recv,
+            value: 0,
+            ticks: 0,
+            last_tick: Instant::now(),
+            buzzer,
+        }
     }
 
     fn handle_message(&mut self, msg: CounterMessage) {
@@ -40,9 +53,26 @@ impl Counter {
             }
             CounterMessage::SetCount { new_value } => {
                 self.value = new_value;
+                if let Some(buzzer) = &self.buzzer {
+                    // Sets land between ticks, the count reaches 0 exactly new_value ticks later
+                    let into_tick = (self.last_tick.elapsed().as_secs_f64() * 60.0).min(0.99);
+                    let now = self.ticks as f64 + into_tick;
+                    buzzer.schedule(now, (self.ticks + new_value as u64) as f64);
+                }
             }
         }
     }
+
+    fn tick(&mut self) {
+        if self.value > 0 {
+            self.value -= 1;
+        }
+        self.ticks += 1;
+        self.last_tick = Instant::now();
+        if let Some(buzzer) = &self.buzzer {
+            buzzer.advance(self.ticks as f64);
+        }
+    }
 }
 
 pub async fn run_counter(mut counter: Counter) {
@@ -50,18 +80,15 @@ pub async fn run_counter(mut counter: Counter) {
     let mut ival = interval(Duration::from_secs_f64(crate::util::hz_to_secs("60Hz")));
     ival.set_missed_tick_behavior(MissedTickBehavior::Burst);
     loop {
-        ival.tick().await;
         tokio::select! {
-            Some(msg) = counter.recv.recv() => { counter.handle_message(msg) },
-            else => {
+            _ = ival.tick() => counter.tick(),
+            msg = counter.recv.recv() => match msg {
+                Some(msg) => counter.handle_message(msg),
                 // The counter.recv should stay alive as long as the Chip8 is running
                 // This branch is activated when the Chip8 stops executing.
-                break
+                None => break,
             },
         };
-        if counter.value > 0 {
-            counter.value -= 1;
-        }
     }
 }
 
@@ -70,10 +97,25 @@ pub struct CounterHandle {
     sender: mpsc::Sender<CounterMessage>,
 }
 
+impl Default for CounterHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CounterHandle {
     pub fn new() -> Self {
+        Self::spawn(None)
+    }
+
+    /// A sound timer, scheduling the buzzer as it is set
+    pub fn with_buzzer(buzzer: Buzzer) -> Self {
+        Self::spawn(Some(buzzer))
+    }
+
+    fn spawn(buzzer: Option<Buzzer>) -> Self {
         let (sender, recv) = mpsc::channel(10);
-        let actor = Counter::new(recv);
+        let actor = Counter::new(recv, buzzer);
         tokio::spawn(run_counter(actor));
 
         Self { sender }
@@ -91,3 +133,42 @@ impl CounterHandle {
         let _ = self.sender.send(msg).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticks(n: u64) -> Duration {
+        Duration::from_secs_f64(n as f64 / 60.0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_down_at_60hz() {
+        let timer = CounterHandle::new();
+        timer.set(30).await;
+        tokio::time::sleep(ticks(10)).await;
+        assert!((19..=21).contains(&timer.get().await));
+        tokio::time::sleep(ticks(25)).await;
+        assert_eq!(timer.get().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sound_timer_schedules_the_buzzer() {
+        let buzzer = Buzzer::new();
+        let timer = CounterHandle::with_buzzer(buzzer.clone());
+        tokio::time::sleep(ticks(5)).await;
+        assert!(!buzzer.is_on());
+        timer.set(30).await;
+        tokio::time::sleep(ticks(10)).await;
+        assert!(buzzer.is_on());
+        // Cut short by the ROM
+        timer.set(0).await;
+        tokio::time::sleep(ticks(1)).await;
+        assert!(!buzzer.is_on());
+        timer.set(3).await;
+        tokio::time::sleep(ticks(1)).await;
+        assert!(buzzer.is_on());
+        tokio::time::sleep(ticks(5)).await;
+        assert!(!buzzer.is_on());
+    }
+}
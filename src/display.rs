@@ -0,0 +1,238 @@
+// display.rs: anti-flicker filters between the video memory and the screen
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::palette::{Palette, Rgb};
+use crate::vram::Memory;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    // Pixels exactly as they are in video memory
+    #[default]
+    Direct,
+    // Pixels fade out over a number of frames once switched off
+    Phosphor,
+    // Pixels lit in either of the last two frames are shown
+    Blend,
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 3] = [RenderMode::Direct, RenderMode::Phosphor, RenderMode::Blend];
+}
+
+impl fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RenderMode::Direct => "direct",
+            RenderMode::Phosphor => "phosphor",
+            RenderMode::Blend => "blend",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RenderMode::ALL
+            .into_iter()
+            .find(|m| m.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown render mode '{}', expected direct, phosphor or blend",
+                    s
+                )
+            })
+    }
+}
+
+/// How the screen is scaled up to fill the window
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleMode {
+    // Whole multiples of the chip8 resolution, every pixel the same size
+    #[default]
+    Integer,
+    // As large as fits, pixels may differ in size by one
+    Fit,
+}
+
+impl ScaleMode {
+    pub const ALL: [ScaleMode; 2] = [ScaleMode::Integer, ScaleMode::Fit];
+}
+
+impl fmt::Display for ScaleMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ScaleMode::Integer => "integer",
+            ScaleMode::Fit => "fit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ScaleMode::ALL
+            .into_iter()
+            .find(|m| m.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown scale mode '{}', expected integer or fit", s))
+    }
+}
+
+/// Where a `screen` (width, height) sized display goes in a `window` sized area:
+/// (x, y, width, height), keeping the aspect ratio and centred, letterboxed on the sides
+pub fn viewport(
+    window: (u32, u32),
+    screen: (usize, usize),
+    scale: ScaleMode,
+) -> (i32, i32, u32, u32) {
+    let (ww, wh) = (window.0 as u64, window.1 as u64);
+    let (sw, sh) = (screen.0 as u64, screen.1 as u64);
+    let (w, h) = match scale {
+        ScaleMode::Integer => {
+            let s = (ww / sw).min(wh / sh).max(1);
+            (sw * s, sh * s)
+        }
+        ScaleMode::Fit => {
+            if ww * sh <= wh * sw {
+                (ww, ww * sh / sw)
+            } else {
+                (wh * sw / sh, wh)
+            }
+        }
+    };
+    let x = (ww as i64 - w as i64) / 2;
+    let y = (wh as i64 - h as i64) / 2;
+    (x as i32, y as i32, w as u32, h as u32)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplaySettings {
+    pub mode: RenderMode,
+    // How many frames a pixel takes to fade out in phosphor mode
+    pub phosphor_frames: u8,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            mode: RenderMode::Direct,
+            phosphor_frames: 4,
+        }
+    }
+}
+
+/// Per pixel brightness carried over from frame to frame
+#[derive(Debug, Default)]
+pub struct Persistence {
+    width: usize,
+    height: usize,
+    // 0.0 (off) - 1.0 (fully lit)
+    intensity: Vec<f32>,
+    // Last non zero value of each pixel, so fading pixels keep their colour
+    value: Vec<u8>,
+    previous: Vec<u8>,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed in the frame about to be shown, once per displayed frame
+    pub fn update(&mut self, frame: &Memory, settings: DisplaySettings) {
+        let (width, height) = frame.size();
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.intensity = vec![0.0; width * height];
+            self.value = vec![0; width * height];
+            self.previous = vec![0; width * height];
+        }
+        let fade = 1.0 / settings.phosphor_frames.max(1) as f32;
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let now = frame[(x, y)] as u8;
+                let before = self.previous[i];
+                if now > 0 {
+                    self.value[i] = now;
+                }
+                self.intensity[i] = match settings.mode {
+                    RenderMode::Direct => (now > 0) as u8 as f32,
+                    RenderMode::Blend => {
+                        if now == 0 && before > 0 {
+                            self.value[i] = before;
+                        }
+                        (now > 0 || before > 0) as u8 as f32
+                    }
+                    RenderMode::Phosphor => {
+                        if now > 0 {
+                            1.0
+                        } else {
+                            (self.intensity[i] - fade).max(0.0)
+                        }
+                    }
+                };
+                self.previous[i] = now;
+            }
+        }
+    }
+
+    /// (width, height) of the last frame fed in
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Whether the next update() could change anything without the frame changing
+    pub fn is_fading(&self) -> bool {
+        self.intensity
+            .iter()
+            .zip(self.previous.iter())
+            .any(|(i, p)| *p == 0 && *i > 0.0)
+    }
+
+    /// Write the screen as packed RGB24 rows, `pitch` bytes apart
+    pub fn write_rgb(&self, palette: &Palette, out: &mut [u8], pitch: usize) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let offset = y * pitch + x * 3;
+                out[offset..offset + 3].copy_from_slice(&self.color(x, y, palette));
+            }
+        }
+    }
+
+    /// Colour to draw pixel (x, y) with
+    pub fn color(&self, x: usize, y: usize, palette: &Palette) -> Rgb {
+        let i = y * self.width + x;
+        let bg = palette.background();
+        let t = self.intensity.get(i).copied().unwrap_or(0.0);
+        if t <= 0.0 {
+            return bg;
+        }
+        let fg = palette.color(self.value[i] as usize);
+        std::array::from_fn(|c| (bg[c] as f32 + (fg[c] as f32 - bg[c] as f32) * t).round() as u8)
+    }
+}
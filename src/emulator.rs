@@ -0,0 +1,67 @@
+// emulator.rs: the actors making up a running chip8, started together
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::PathBuf;
+
+use crate::audio::Buzzer;
+use crate::chip8::Chip8Handle;
+use crate::fuse::FuseHandle;
+use crate::input::InputHandle;
+use crate::vram::{ScreenSize, VRAMHandle};
+use crate::watch;
+
+/// Handles to a running emulator, what frontends and embedders drive it through
+#[derive(Debug)]
+pub struct Emulator {
+    pub fuse: FuseHandle,
+    pub input: InputHandle,
+    pub video: VRAMHandle,
+    // Sound timer edges, for audio outputs to play back
+    pub buzzer: Buzzer,
+    pub chip8: Chip8Handle,
+}
+
+impl Emulator {
+    /// Spawn the emulator's tasks, paused, with `rom` loaded if there is one.
+    /// `cycle_secs` is the time per instruction, see `util::hz_to_secs`.
+    /// Must be called from within a tokio runtime.
+    pub fn start(cycle_secs: f64, rom: Option<Vec<u8>>) -> Self {
+        let video = VRAMHandle::new(ScreenSize::S);
+        let input = InputHandle::new();
+        let fuse = FuseHandle::new();
+        let chip8 = Chip8Handle::new(cycle_secs, rom, input.clone(), video.clone(), fuse.clone());
+        Self {
+            fuse,
+            input,
+            video,
+            buzzer: chip8.buzzer.clone(),
+            chip8,
+        }
+    }
+
+    /// Reload the ROM from `path` whenever the file changes, restarting from power on or
+    /// from the save state in `state_file` (see `State::to_bytes`) if there is one.
+    /// Call it before unpausing, the state it is called in is what reloads reset to.
+    pub async fn watch(&self, path: PathBuf, state_file: Option<PathBuf>) {
+        let power_on = self.chip8.save_state().await;
+        watch::spawn(
+            path,
+            state_file,
+            power_on,
+            self.chip8.clone(),
+            self.fuse.clone(),
+        );
+    }
+}
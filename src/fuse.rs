@@ -46,6 +46,12 @@ pub struct FuseHandle {
     send: broadcast::Sender<FuseMessage>,
 }
 
+impl Default for FuseHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FuseHandle {
     pub fn new() -> Self {
         let (send, recv) = broadcast::channel(1);
@@ -13,13 +13,54 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
 use tokio::sync::{mpsc, oneshot};
-use tokio::time::{interval, Duration, MissedTickBehavior};
+
+// How many key events are kept around for FX0A and recorders to catch up on
+const EVENT_HISTORY: usize = 256;
+// A key counts as being polled by the ROM if EX9E/EXA1 checked it this recently
+const POLL_WINDOW: Duration = Duration::from_millis(250);
+
+/// A key changing state, `seq` increases by one with every event
+#[derive(Copy, Clone, Debug)]
+pub struct KeyEvent {
+    pub seq: u64,
+    pub key: u8,
+    pub pressed: bool,
+    pub at: Instant,
+}
+
+/// The first key in `events` to be both pressed and released, what FX0A waits for.
+/// Keys already held when `events` start only count once pressed again.
+pub fn first_release(events: &[KeyEvent]) -> Option<&KeyEvent> {
+    events.iter().find(|e| {
+        !e.pressed
+            && events
+                .iter()
+                .any(|p| p.pressed && p.key == e.key && p.seq < e.seq)
+    })
+}
+
+/// Snapshot of the keypad for displaying it
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KeypadState {
+    pub held: [bool; 16],
+    pub polled: [bool; 16],
+}
 
 #[derive(Debug)]
 pub struct Input {
     recv: mpsc::Receiver<InputMessage>,
     keypad: [bool; 16],
+    // Pressed at some point since the key was last polled,
+    // so taps shorter than the time between two polls still register
+    latched: [bool; 16],
+    // When the ROM last checked each key
+    polled: [Option<Instant>; 16],
+    events: VecDeque<KeyEvent>,
+    next_seq: u64,
 }
 
 #[derive(Debug)]
@@ -34,6 +75,16 @@ pub enum InputMessage {
         key: u8,
         respond_to: oneshot::Sender<bool>,
     },
+    Keypad {
+        respond_to: oneshot::Sender<KeypadState>,
+    },
+    LastSeq {
+        respond_to: oneshot::Sender<u64>,
+    },
+    EventsSince {
+        seq: u64,
+        respond_to: oneshot::Sender<Vec<KeyEvent>>,
+    },
 }
 
 impl Input {
@@ -41,40 +92,79 @@ impl Input {
         Input {
             recv,
             keypad: [false; 16],
+            latched: [false; 16],
+            polled: [None; 16],
+            events: VecDeque::with_capacity(EVENT_HISTORY),
+            next_seq: 1,
         }
     }
 
+    fn push_event(&mut self, key: u8, pressed: bool) {
+        if self.events.len() == EVENT_HISTORY {
+            self.events.pop_front();
+        }
+        self.events.push_back(KeyEvent {
+            seq: self.next_seq,
+            key,
+            pressed,
+            at: Instant::now(),
+        });
+        self.next_seq += 1;
+    }
+
     fn handle_message(&mut self, msg: InputMessage) {
         match msg {
             InputMessage::KeyDown { key } => {
+                let key = key & 0xF;
                 self.keypad[key as usize] = true;
+                self.latched[key as usize] = true;
+                self.push_event(key, true);
             }
             InputMessage::KeyUp { key } => {
+                let key = key & 0xF;
                 self.keypad[key as usize] = false;
+                self.push_event(key, false);
             }
             InputMessage::Status { key, respond_to } => {
-                let status = self.keypad[key as usize];
-                respond_to.send(status).unwrap();
+                let key = (key & 0xF) as usize;
+                let status = self.keypad[key] || self.latched[key];
+                self.latched[key] = false;
+                self.polled[key] = Some(Instant::now());
+                let _ = respond_to.send(status);
+            }
+            InputMessage::Keypad { respond_to } => {
+                let polled = self
+                    .polled
+                    .map(|p| p.is_some_and(|at| at.elapsed() < POLL_WINDOW));
+                let _ = respond_to.send(KeypadState {
+                    held: self.keypad,
+                    polled,
+                });
+            }
+            InputMessage::LastSeq { respond_to } => {
+                let _ = respond_to.send(self.next_seq - 1);
+            }
+            InputMessage::EventsSince { seq, respond_to } => {
+                let events = self
+                    .events
+                    .iter()
+                    .filter(|e| e.seq > seq)
+                    .copied()
+                    .collect();
+                let _ = respond_to.send(events);
             }
         }
     }
 }
 
 pub async fn run_input(mut input: Input) {
-    // Count down at 60 Hz
-    let mut ival = interval(Duration::from_secs_f64(crate::util::hz_to_secs("60Hz")));
-    ival.set_missed_tick_behavior(MissedTickBehavior::Skip);
-    loop {
-        ival.tick().await;
-        tokio::select! {
-            Some(msg) = input.recv.recv() => { input.handle_message(msg) },
-            else => {
-                // The input.recv should stay alive as long as the Chip8 is running
-                // This branch is activated when the Chip8 stops executing.
-                break
-            },
-        };
+    // Handle every message as soon as it arrives, key presses are latched
+    // so there is no need to pace them to the 60 Hz timers
+    while let Some(msg) = input.recv.recv().await {
+        input.handle_message(msg)
     }
+    // The input.recv should stay alive as long as the Chip8 is running
+    // Getting here means the Chip8 stopped executing.
 }
 
 #[derive(Clone, Debug)]
@@ -82,9 +172,15 @@ pub struct InputHandle {
     sender: mpsc::Sender<InputMessage>,
 }
 
+impl Default for InputHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InputHandle {
     pub fn new() -> Self {
-        let (sender, recv) = mpsc::channel(10);
+        let (sender, recv) = mpsc::channel(64);
         let actor = Input::new(recv);
         tokio::spawn(run_input(actor));
 
@@ -101,6 +197,7 @@ impl InputHandle {
         let _ = self.sender.send(msg).await;
     }
 
+    /// Whether the key is held, or was pressed since it was last asked about
     pub async fn pressed(&self, key: u8) -> bool {
         let (send, recv) = oneshot::channel();
         let msg = InputMessage::Status {
@@ -110,4 +207,96 @@ impl InputHandle {
         let _ = self.sender.send(msg).await;
         recv.await.unwrap()
     }
+
+    /// Which keys are held, and which keys the ROM is checking
+    pub async fn keypad(&self) -> KeypadState {
+        let (send, recv) = oneshot::channel();
+        let msg = InputMessage::Keypad { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.unwrap()
+    }
+
+    /// Sequence number of the most recent key event, 0 if there has been none
+    pub async fn last_seq(&self) -> u64 {
+        let (send, recv) = oneshot::channel();
+        let msg = InputMessage::LastSeq { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.unwrap()
+    }
+
+    /// Key events newer than `seq`, oldest first
+    pub async fn events_since(&self, seq: u64) -> Vec<KeyEvent> {
+        let (send, recv) = oneshot::channel();
+        let msg = InputMessage::EventsSince {
+            seq,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+        recv.await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: u64, key: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            seq,
+            key,
+            pressed,
+            at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn fx0a_waits_for_press_and_release() {
+        assert!(first_release(&[]).is_none());
+        // Held before FX0A started waiting
+        assert!(first_release(&[event(1, 5, false)]).is_none());
+        assert!(first_release(&[event(1, 5, true)]).is_none());
+        let events = [event(1, 5, true), event(2, 5, false)];
+        assert_eq!(first_release(&events).unwrap().key, 5);
+    }
+
+    #[test]
+    fn fx0a_takes_the_first_key_released() {
+        let events = [
+            event(1, 0xA, false),
+            event(2, 0xA, true),
+            event(3, 0xB, true),
+            event(4, 0xB, false),
+            event(5, 0xA, false),
+        ];
+        assert_eq!(first_release(&events).unwrap().key, 0xB);
+    }
+
+    #[tokio::test]
+    async fn taps_are_latched_until_polled() {
+        let input = InputHandle::new();
+        input.key_down(3).await;
+        input.key_up(3).await;
+        assert!(input.pressed(3).await);
+        assert!(!input.pressed(3).await);
+        assert!(!input.pressed(4).await);
+
+        input.key_down(3).await;
+        assert!(input.pressed(3).await);
+        assert!(input.pressed(3).await);
+        assert!(input.keypad().await.held[3]);
+        assert!(input.keypad().await.polled[3]);
+    }
+
+    #[tokio::test]
+    async fn events_since_a_sequence_number() {
+        let input = InputHandle::new();
+        assert_eq!(input.last_seq().await, 0);
+        input.key_down(0x1F).await;
+        let seq = input.last_seq().await;
+        input.key_up(0xF).await;
+        let events = input.events_since(seq).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].key, events[0].pressed), (0xF, false));
+        assert_eq!(input.events_since(0).await.len(), 2);
+    }
 }
@@ -0,0 +1,302 @@
+// keymap.rs: named keyboard layouts for the chip8 keypad
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::rom_settings::RomSettings;
+use crate::util;
+
+const KEYMAPS_FILE: &str = "keymaps.toml";
+
+/// The chip8 keypad as it is physically laid out, row by row
+pub const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+/// How key names in a layout are matched against the keyboard
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyMode {
+    // Key position (scancode), the same block of keys on QWERTY, AZERTY, Dvorak...
+    #[default]
+    Physical,
+    // Key label (keycode), follows the OS keyboard layout
+    Symbolic,
+}
+
+impl KeyMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            KeyMode::Physical => "physical",
+            KeyMode::Symbolic => "symbolic",
+        }
+    }
+}
+
+/// A named layout; `keys[n]` is the name of the key bound to chip8 key `n`.
+/// Names are whatever the frontend uses to identify a key (SDL key names for now).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keymap {
+    pub name: String,
+    #[serde(default)]
+    pub mode: KeyMode,
+    pub keys: [String; 16],
+}
+
+impl Keymap {
+    fn from_names(name: &str, keys: [&str; 16]) -> Self {
+        Self {
+            name: String::from(name),
+            mode: KeyMode::Physical,
+            keys: keys.map(String::from),
+        }
+    }
+
+    /// 1234/QWER/ASDF/ZXCV, the top left block of a QWERTY keyboard
+    pub fn qwerty() -> Self {
+        Self::from_names(
+            "QWERTY",
+            [
+                "X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V",
+            ],
+        )
+    }
+
+    /// Digits on their keypad counterparts, A-F on the surrounding keys
+    pub fn numpad() -> Self {
+        Self::from_names(
+            "Numeric Keypad",
+            [
+                "Keypad 0",
+                "Keypad 1",
+                "Keypad 2",
+                "Keypad 3",
+                "Keypad 4",
+                "Keypad 5",
+                "Keypad 6",
+                "Keypad 7",
+                "Keypad 8",
+                "Keypad 9",
+                "Keypad /",
+                "Keypad *",
+                "Keypad -",
+                "Keypad +",
+                "Keypad Enter",
+                "Keypad .",
+            ],
+        )
+    }
+
+    pub fn builtins() -> Vec<Self> {
+        vec![Self::qwerty(), Self::numpad()]
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        Self::builtins().iter().any(|b| b.name == self.name)
+    }
+
+    /// Pick the name this layout matches on, given a key's physical and symbolic names
+    pub fn key_name<'a>(&self, physical: &'a str, symbolic: &'a str) -> &'a str {
+        match self.mode {
+            KeyMode::Physical => physical,
+            KeyMode::Symbolic => symbolic,
+        }
+    }
+
+    /// chip8 key bound to the named key, if any
+    pub fn lookup(&self, key_name: &str) -> Option<u8> {
+        self.keys
+            .iter()
+            .position(|k| k.eq_ignore_ascii_case(key_name))
+            .map(|k| k as u8)
+    }
+
+    /// Bind `key_name` to chip8 key `key`, unbinding it from any other chip8 key
+    pub fn bind(&mut self, key: u8, key_name: &str) {
+        for k in self.keys.iter_mut() {
+            if k.eq_ignore_ascii_case(key_name) {
+                k.clear();
+            }
+        }
+        self.keys[key as usize] = String::from(key_name);
+    }
+}
+
+/// Every known layout, plus which one is the default
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Keymaps {
+    #[serde(default = "default_layout_name")]
+    pub default: String,
+    #[serde(default, rename = "layout")]
+    pub layouts: Vec<Keymap>,
+    // ROM name => layout name, from before layouts were saved with the rest of roms.toml
+    #[serde(default)]
+    pub games: BTreeMap<String, String>,
+}
+
+fn default_layout_name() -> String {
+    Keymap::qwerty().name
+}
+
+impl Default for Keymaps {
+    fn default() -> Self {
+        Self {
+            default: default_layout_name(),
+            layouts: Keymap::builtins(),
+            games: BTreeMap::new(),
+        }
+    }
+}
+
+impl Keymaps {
+    /// Built in layouts merged with the user's keymaps file, if there is one
+    pub fn load() -> Self {
+        util::load_toml::<Self>(KEYMAPS_FILE).with_builtins()
+    }
+
+    fn with_builtins(mut self) -> Self {
+        for builtin in Keymap::builtins() {
+            if self.get(&builtin.name).is_none() {
+                self.layouts.push(builtin);
+            }
+        }
+        self
+    }
+
+    pub fn save(&self) {
+        // Built in layouts are recreated on load, only write them if they were changed
+        let mut to_save = self.clone();
+        to_save.layouts.retain(|l| !Keymap::builtins().contains(l));
+        util::save_toml(KEYMAPS_FILE, &to_save);
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.layouts.iter().map(|l| l.name.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Keymap> {
+        self.layouts.iter().find(|l| l.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Keymap> {
+        self.layouts.iter_mut().find(|l| l.name == name)
+    }
+
+    /// Layout name used by a ROM: its saved keymap, then one picked for its
+    /// file name in older keymaps.toml files, then the default layout
+    pub fn layout_for(&self, settings: &RomSettings, rom_name: &str) -> String {
+        let saved = settings
+            .keymap
+            .as_ref()
+            .or_else(|| self.games.get(rom_name));
+        match saved {
+            Some(name) if self.get(name).is_some() => name.clone(),
+            _ => self.default.clone(),
+        }
+    }
+
+    /// Add a copy of layout `from` named `name`, replacing any existing layout of that name
+    pub fn copy_layout(&mut self, from: &str, name: &str) {
+        let mut copy = self.get(from).cloned().unwrap_or_else(Keymap::qwerty);
+        copy.name = String::from(name);
+        self.layouts.retain(|l| l.name != name);
+        self.layouts.push(copy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Built in layouts merged with the contents of a keymaps file, like `Keymaps::load`
+    fn parse(contents: &str) -> Keymaps {
+        util::parse_toml::<Keymaps>(KEYMAPS_FILE, contents).with_builtins()
+    }
+
+    const KEYMAPS: &str = r#"
+default = "Mine"
+
+[[layout]]
+name = "Mine"
+mode = "symbolic"
+keys = ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"]
+
+[games]
+PONG = "Numeric Keypad"
+"#;
+
+    #[test]
+    fn parse_keymaps_file() {
+        let keymaps = parse(KEYMAPS);
+        assert_eq!(keymaps.default, "Mine");
+        assert_eq!(keymaps.names(), ["Mine", "QWERTY", "Numeric Keypad"]);
+        let mine = keymaps.get("Mine").unwrap();
+        assert_eq!(mine.mode, KeyMode::Symbolic);
+        assert_eq!(mine.lookup("q"), Some(4));
+        assert_eq!(mine.key_name("Physical", "Symbolic"), "Symbolic");
+    }
+
+    #[test]
+    fn malformed_keymaps_file_is_ignored() {
+        let keymaps = parse("default = [");
+        assert_eq!(keymaps.default, "QWERTY");
+        assert_eq!(keymaps.layouts, Keymap::builtins());
+    }
+
+    #[test]
+    fn layouts_round_trip() {
+        let mut keymaps = Keymaps::default();
+        keymaps.copy_layout("QWERTY", "Copy");
+        keymaps.get_mut("Copy").unwrap().bind(0xF, "Space");
+        let parsed = parse(&toml::to_string_pretty(&keymaps).unwrap());
+        assert_eq!(parsed.get("Copy"), keymaps.get("Copy"));
+    }
+
+    #[test]
+    fn bind_moves_a_key() {
+        let mut keymap = Keymap::qwerty();
+        keymap.bind(0x0, "1");
+        assert_eq!(keymap.lookup("1"), Some(0x0));
+        assert_eq!(keymap.keys[0x1], "");
+    }
+
+    #[test]
+    fn layout_for_a_rom() {
+        let keymaps = parse(KEYMAPS);
+        let saved = RomSettings {
+            keymap: Some(String::from("QWERTY")),
+            ..RomSettings::default()
+        };
+        assert_eq!(keymaps.layout_for(&saved, "PONG"), "QWERTY");
+        assert_eq!(
+            keymaps.layout_for(&RomSettings::default(), "PONG"),
+            "Numeric Keypad"
+        );
+        assert_eq!(
+            keymaps.layout_for(&RomSettings::default(), "TETRIS"),
+            "Mine"
+        );
+        let missing = RomSettings {
+            keymap: Some(String::from("Gone")),
+            ..RomSettings::default()
+        };
+        assert_eq!(keymaps.layout_for(&missing, "TETRIS"), "Mine");
+    }
+}
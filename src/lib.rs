@@ -0,0 +1,67 @@
+// lib.rs: the chip8 emulator as a library, frontends included
+// Copyright (C) 2015-2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! A chip8 emulator built from tokio actors: the CPU core, video memory, input and the
+//! delay/sound timers each run as a task and are driven through cloneable handles.
+//!
+//! [`Emulator::start`] spawns all of them (it must be called from inside a tokio runtime).
+//! Read the screen with [`VRAMHandle::subscribe`], press keys with [`InputHandle::key_down`]
+//! and [`InputHandle::key_up`], and stop everything with [`FuseHandle::blow`].
+//!
+//! The `sdl` and `tui` features add the window and terminal frontends in [`ui`];
+//! without them the library has no native dependencies. The `libretro` feature adds the
+//! libretro core API in `libretro`, for building the library as a cdylib with
+//! `cargo rustc --lib --crate-type cdylib`.
+//!
+//! Only the modules and types exported here are public, the actors' messages, the menus
+//! and the ROM watcher are internal.
+pub mod audio;
+pub mod cartridge;
+pub(crate) mod chip8;
+pub(crate) mod config;
+pub(crate) mod counter;
+pub mod display;
+pub(crate) mod emulator;
+pub(crate) mod fuse;
+pub(crate) mod input;
+pub mod keymap;
+pub(crate) mod library;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+pub(crate) mod octo;
+pub mod palette;
+pub(crate) mod recorder;
+pub mod rom;
+pub mod rom_db;
+pub(crate) mod rom_settings;
+pub mod screenshot;
+pub mod ui;
+pub(crate) mod util;
+pub(crate) mod vram;
+pub(crate) mod watch;
+
+pub use audio::{AudioSettings, Buzzer};
+pub use chip8::{Chip8Handle, Quirks, State};
+pub use config::Config;
+pub use counter::CounterHandle;
+pub use display::DisplaySettings;
+pub use emulator::Emulator;
+pub use fuse::FuseHandle;
+pub use input::{InputHandle, KeyEvent, KeypadState};
+pub use library::RomLibrary;
+pub use palette::Palette;
+pub use rom_settings::{RomSettings, RomSettingsStore};
+pub use util::{format_speed, parse_size, parse_speed, rom_name, test_roms};
+pub use vram::{Frame, Memory, ScreenSize, VRAMHandle};
@@ -0,0 +1,71 @@
+// library.rs: favourite and recently played ROMs
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util;
+
+const LIBRARY_FILE: &str = "library.toml";
+const MAX_RECENT: usize = 10;
+
+/// ROM files the user marked or played, as absolute paths
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RomLibrary {
+    pub favourites: Vec<PathBuf>,
+    // Most recent first
+    pub recent: Vec<PathBuf>,
+}
+
+impl RomLibrary {
+    pub fn load() -> Self {
+        util::load_toml(LIBRARY_FILE)
+    }
+
+    pub fn save(&self) {
+        util::save_toml(LIBRARY_FILE, self);
+    }
+
+    /// Move `rom` to the top of the recently played list
+    pub fn played(&mut self, rom: &Path) {
+        let rom = absolute(rom);
+        self.recent.retain(|r| *r != rom);
+        self.recent.insert(0, rom);
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    pub fn is_favourite(&self, rom: &Path) -> bool {
+        self.favourites.contains(&absolute(rom))
+    }
+
+    pub fn toggle_favourite(&mut self, rom: &Path) {
+        let rom = absolute(rom);
+        if self.favourites.contains(&rom) {
+            self.favourites.retain(|r| *r != rom);
+        } else {
+            self.favourites.push(rom);
+        }
+    }
+}
+
+/// `path` made absolute, so the same ROM is listed once wherever it was opened from
+fn absolute(path: &Path) -> PathBuf {
+    // Paths into zip files (pack.zip/GAME.ch8) can't be canonicalized
+    path.canonicalize()
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| PathBuf::from(path))
+}
@@ -0,0 +1,653 @@
+// libretro.rs: the libretro core API, for running inside RetroArch and other libretro frontends
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::ffi::{c_char, c_void, CStr};
+use std::io;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::audio::{AudioSettings, ToneGenerator};
+use crate::chip8::State;
+use crate::display::{DisplaySettings, Persistence};
+use crate::emulator::Emulator;
+use crate::keymap::{Keymap, Keymaps};
+use crate::palette::Palette;
+use crate::rom_settings::{RomSettings, RomSettingsStore};
+use crate::ui::FRAME;
+use crate::{rom, util};
+
+pub const API_VERSION: u32 = 1;
+pub const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+
+pub const ENVIRONMENT_SET_PIXEL_FORMAT: u32 = 10;
+pub const ENVIRONMENT_SET_INPUT_DESCRIPTORS: u32 = 11;
+pub const PIXEL_FORMAT_XRGB8888: i32 = 1;
+pub const DEVICE_JOYPAD: u32 = 1;
+pub const DEVICE_KEYBOARD: u32 = 3;
+const REGION_NTSC: u32 = 0;
+
+// chip8 key for each joypad button, in RETRO_DEVICE_ID_JOYPAD_* order:
+// B, Y, Select, Start, Up, Down, Left, Right, A, X, L, R, L2, R2, L3, R3.
+// The d-pad is on 2/8/4/6 and A on 5, what most games move and fire with.
+const JOYPAD_KEYS: [u8; 16] = [
+    0x0, 0x1, 0xE, 0xF, 0x2, 0x8, 0x4, 0x6, 0x5, 0x3, 0x7, 0x9, 0xA, 0xB, 0xC, 0xD,
+];
+const KEY_NAMES: [&CStr; 16] = [
+    c"Keypad 0",
+    c"Keypad 1",
+    c"Keypad 2",
+    c"Keypad 3",
+    c"Keypad 4",
+    c"Keypad 5",
+    c"Keypad 6",
+    c"Keypad 7",
+    c"Keypad 8",
+    c"Keypad 9",
+    c"Keypad A",
+    c"Keypad B",
+    c"Keypad C",
+    c"Keypad D",
+    c"Keypad E",
+    c"Keypad F",
+];
+
+#[repr(C)]
+pub struct SystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    pub base_width: u32,
+    pub base_height: u32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    pub geometry: GameGeometry,
+    pub timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct InputDescriptor {
+    pub port: u32,
+    pub device: u32,
+    pub index: u32,
+    pub id: u32,
+    pub description: *const c_char,
+}
+
+pub type EnvironmentFn = unsafe extern "C" fn(cmd: u32, data: *mut c_void) -> bool;
+pub type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: u32, height: u32, pitch: usize);
+pub type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+pub type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type InputPollFn = unsafe extern "C" fn();
+pub type InputStateFn = unsafe extern "C" fn(port: u32, device: u32, index: u32, id: u32) -> i16;
+
+/// What the frontend handed us to talk back to it with
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+/// How a game is set up: its saved settings and keymap
+#[derive(Clone)]
+struct Setup {
+    settings: RomSettings,
+    keymap: Keymap,
+}
+
+impl Setup {
+    /// What roms.toml and keymaps.toml in the config directory say for the ROM
+    fn load(rom: &[u8], rom_name: &str) -> Self {
+        let settings = RomSettingsStore::load().get(&rom::sha1(rom), rom_name);
+        let keymaps = Keymaps::load();
+        let keymap = keymaps
+            .get(&keymaps.layout_for(&settings, rom_name))
+            .cloned()
+            .unwrap_or_else(Keymap::qwerty);
+        Self { settings, keymap }
+    }
+}
+
+impl Default for Setup {
+    fn default() -> Self {
+        Self {
+            settings: RomSettings::default(),
+            keymap: Keymap::qwerty(),
+        }
+    }
+}
+
+/// A loaded game. The emulator's tasks run on a runtime with paused time,
+/// each retro_run moves it forward by exactly one 60Hz frame.
+struct Core {
+    rt: Runtime,
+    emu: Emulator,
+    rom: Vec<u8>,
+    rom_name: String,
+    setup: Setup,
+    held: [bool; 16],
+    display: DisplaySettings,
+    palette: Palette,
+    persistence: Persistence,
+    tone: ToneGenerator,
+    rgb: Vec<u8>,
+    video: Vec<u32>,
+    audio: Vec<f32>,
+    samples: Vec<i16>,
+}
+
+impl Core {
+    fn start(rom: Vec<u8>, rom_name: String, setup: Setup) -> io::Result<Self> {
+        // Paused time only moves when every task is waiting on a timer, so each retro_run
+        // sleeping for a frame runs exactly a frame of instructions and timer ticks, however
+        // long the frontend takes between calls. This is why the libretro feature turns on
+        // tokio's test-util.
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+        let speed = setup
+            .settings
+            .speed()
+            .unwrap_or_else(|| util::hz_to_secs(util::DEFAULT_SPEED));
+        let emu = {
+            let _guard = rt.enter();
+            Emulator::start(speed, Some(rom.clone()))
+        };
+        rt.block_on(async {
+            let quirks = setup.settings.quirks.unwrap_or_default();
+            emu.chip8.set_quirks(quirks).await;
+            emu.chip8.unpause().await;
+        });
+
+        let display = setup.settings.display.unwrap_or_default();
+        let palette = setup.settings.palette().unwrap_or_else(Palette::classic);
+        let tone = ToneGenerator::new(
+            AudioSettings::default(),
+            SAMPLE_RATE as i32,
+            emu.buzzer.clone(),
+        );
+        debug!("Started libretro core with {}", rom_name);
+        Ok(Self {
+            rt,
+            emu,
+            rom,
+            rom_name,
+            setup,
+            held: [false; 16],
+            display,
+            palette,
+            persistence: Persistence::new(),
+            tone,
+            rgb: Vec::new(),
+            video: Vec::new(),
+            audio: vec![0.0; SAMPLES_PER_FRAME],
+            samples: vec![0; SAMPLES_PER_FRAME * 2],
+        })
+    }
+
+    fn stop(&self) {
+        self.emu.fuse.blow();
+    }
+
+    fn run(&mut self, cb: &Callbacks) {
+        if let Some(poll) = cb.input_poll {
+            unsafe { poll() };
+        }
+        if let Some(state) = cb.input_state {
+            self.read_keypad(state);
+        }
+        self.rt.block_on(async { tokio::time::sleep(FRAME).await });
+        if let Some(refresh) = cb.video_refresh {
+            self.render_video(refresh);
+        }
+        if let Some(batch) = cb.audio_sample_batch {
+            self.render_audio(batch);
+        }
+    }
+
+    /// Joypad 1 and the keyboard, through the ROM's keymap, onto the chip8 keypad
+    fn read_keypad(&mut self, state: InputStateFn) {
+        let mut pressed = [false; 16];
+        for (id, key) in JOYPAD_KEYS.iter().enumerate() {
+            if unsafe { state(0, DEVICE_JOYPAD, 0, id as u32) } != 0 {
+                pressed[*key as usize] = true;
+            }
+        }
+        for (key, name) in self.setup.keymap.keys.iter().enumerate() {
+            // RETROK_* match ASCII for digits and lower case letters
+            let code = match name.as_bytes() {
+                [c] if c.is_ascii_alphanumeric() => c.to_ascii_lowercase() as u32,
+                _ => continue,
+            };
+            if unsafe { state(0, DEVICE_KEYBOARD, 0, code) } != 0 {
+                pressed[key] = true;
+            }
+        }
+
+        let input = &self.emu.input;
+        for (key, (now, held)) in pressed.iter().zip(self.held.iter_mut()).enumerate() {
+            if *now != *held {
+                *held = *now;
+                if *now {
+                    self.rt.block_on(input.key_down(key as u8));
+                } else {
+                    self.rt.block_on(input.key_up(key as u8));
+                }
+            }
+        }
+    }
+
+    fn render_video(&mut self, refresh: VideoRefreshFn) {
+        let frame = self.emu.video.frame();
+        self.persistence.update(&frame.mem, self.display);
+        let (width, height) = self.persistence.size();
+        self.rgb.resize(width * height * 3, 0);
+        self.persistence
+            .write_rgb(&self.palette, &mut self.rgb, width * 3);
+        self.video.clear();
+        self.video.extend(
+            self.rgb
+                .chunks_exact(3)
+                .map(|c| (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32),
+        );
+        unsafe {
+            refresh(
+                self.video.as_ptr() as *const c_void,
+                width as u32,
+                height as u32,
+                width * 4,
+            )
+        };
+    }
+
+    fn render_audio(&mut self, batch: AudioSampleBatchFn) {
+        self.tone.render(&mut self.audio);
+        for (i, sample) in self.audio.iter().enumerate() {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.samples[i * 2] = pcm;
+            self.samples[i * 2 + 1] = pcm;
+        }
+        // Frontends may take fewer frames than offered, keep going until they're all in
+        let mut written = 0;
+        while written < SAMPLES_PER_FRAME {
+            let remaining = &self.samples[written * 2..];
+            let taken = unsafe { batch(remaining.as_ptr(), SAMPLES_PER_FRAME - written) };
+            if taken == 0 {
+                break;
+            }
+            written += taken;
+        }
+    }
+}
+
+/// # Safety
+/// `cb` must stay callable until retro_deinit
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(cb);
+}
+
+/// # Safety
+/// `cb` must stay callable until retro_deinit
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+/// Unused, audio goes out a frame at a time through retro_set_audio_sample_batch
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+/// # Safety
+/// `cb` must stay callable until retro_deinit
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+/// # Safety
+/// `cb` must stay callable until retro_deinit
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+/// # Safety
+/// `cb` must stay callable until retro_deinit
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    retro_unload_game();
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> u32 {
+    API_VERSION
+}
+
+/// # Safety
+/// `info` must point to a writable retro_system_info
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: c"rusty_chips".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8|gif".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a writable retro_system_av_info
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: 64,
+            base_height: 32,
+            max_width: 128,
+            max_height: 64,
+            aspect_ratio: 2.0,
+        },
+        timing: SystemTiming {
+            fps: 60.0,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+/// Only the one kind of controller
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: u32, _device: u32) {}
+
+/// Start the game over from a fresh machine
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    let mut core = CORE.lock().unwrap();
+    if let Some(old) = core.take() {
+        old.stop();
+        match Core::start(old.rom.clone(), old.rom_name.clone(), old.setup.clone()) {
+            Ok(new) => *core = Some(new),
+            Err(e) => warn!("Unable to restart: {}", e),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let cb = callbacks();
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        core.run(&cb);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    State::SIZE
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let Some(core) = core.as_ref() else {
+        return false;
+    };
+    if size < State::SIZE {
+        return false;
+    }
+    let bytes = core.rt.block_on(core.emu.chip8.save_state()).to_bytes();
+    slice::from_raw_parts_mut(data as *mut u8, bytes.len()).copy_from_slice(&bytes);
+    true
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let Some(core) = core.as_ref() else {
+        return false;
+    };
+    match State::from_bytes(slice::from_raw_parts(data as *const u8, size)) {
+        Some(state) => {
+            core.rt.block_on(core.emu.chip8.load_state(state));
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: u32, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` must be null or point to a retro_game_info whose data holds `size` bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+    let bytes = slice::from_raw_parts(game.data as *const u8, game.size).to_vec();
+    let rom_name = if game.path.is_null() {
+        String::new()
+    } else {
+        util::rom_name(&CStr::from_ptr(game.path).to_string_lossy())
+    };
+    let rom = match rom::from_bytes(bytes, None) {
+        Ok(rom) => rom,
+        Err(e) => {
+            warn!("Unable to load ROM: {}", e);
+            return false;
+        }
+    };
+    // A cartridge's speed, colours and quirks become the ROM's settings
+    if let Some(options) = &rom.options {
+        let mut store = RomSettingsStore::load();
+        if store.adopt(&rom::sha1(&rom.bytes), &rom_name, options.settings()) {
+            store.save();
+        }
+    }
+    let rom = rom.bytes;
+
+    if let Some(env) = callbacks().environment {
+        let mut format = PIXEL_FORMAT_XRGB8888;
+        if !env(
+            ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut i32 as *mut c_void,
+        ) {
+            warn!("Frontend doesn't support XRGB8888");
+            return false;
+        }
+        let mut descriptors: Vec<InputDescriptor> = JOYPAD_KEYS
+            .iter()
+            .enumerate()
+            .map(|(id, key)| InputDescriptor {
+                port: 0,
+                device: DEVICE_JOYPAD,
+                index: 0,
+                id: id as u32,
+                description: KEY_NAMES[*key as usize].as_ptr(),
+            })
+            .collect();
+        descriptors.push(InputDescriptor {
+            port: 0,
+            device: 0,
+            index: 0,
+            id: 0,
+            description: ptr::null(),
+        });
+        env(
+            ENVIRONMENT_SET_INPUT_DESCRIPTORS,
+            descriptors.as_mut_ptr() as *mut c_void,
+        );
+    }
+
+    let setup = Setup::load(&rom, &rom_name);
+    start_game(rom, rom_name, setup)
+}
+
+/// Replace the running game, if any
+fn start_game(rom: Vec<u8>, rom_name: String, setup: Setup) -> bool {
+    match Core::start(rom, rom_name, setup) {
+        Ok(core) => {
+            if let Some(old) = CORE.lock().unwrap().replace(core) {
+                old.stop();
+            }
+            true
+        }
+        Err(e) => {
+            warn!("Unable to start the emulator: {}", e);
+            false
+        }
+    }
+}
+
+/// No special game types
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: u32,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    if let Some(core) = CORE.lock().unwrap().take() {
+        core.stop();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> u32 {
+    REGION_NTSC
+}
+
+/// RAM lives inside the chip8 task, there is nothing to point at
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: u32) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: u32) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Vec<u8> {
+        let mut bytes = vec![0; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(bytes.as_mut_ptr() as *mut c_void, bytes.len()) });
+        bytes
+    }
+
+    // One test, the core is a global
+    #[test]
+    fn save_states_round_trip() {
+        // V0 += 1, draw the font sprite for V0's low digit, loop
+        let rom = [
+            0x70, 0x01, 0x81, 0x00, 0x62, 0x0F, 0x81, 0x22, 0xF1, 0x29, 0xD3, 0x45, 0x12, 0x00,
+        ];
+        // Not retro_load_game, which reads the settings in the user's config directory
+        assert!(start_game(rom.to_vec(), String::new(), Setup::default()));
+        for _ in 0..5 {
+            retro_run();
+        }
+        let saved = state();
+        assert_eq!(saved.len(), State::SIZE);
+        for _ in 0..5 {
+            retro_run();
+        }
+        assert_ne!(state(), saved);
+
+        assert!(unsafe { retro_unserialize(saved.as_ptr() as *const c_void, saved.len()) });
+        // Screen and timers included
+        assert_eq!(state(), saved);
+
+        // Not a save state
+        let junk = [0u8; 16];
+        assert!(!unsafe { retro_unserialize(junk.as_ptr() as *const c_void, junk.len()) });
+        assert!(!unsafe { retro_serialize(junk.as_ptr() as *mut c_void, junk.len()) });
+        retro_unload_game();
+        assert!(!unsafe { retro_serialize(ptr::null_mut(), State::SIZE) });
+    }
+}
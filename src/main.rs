@@ -13,78 +13,298 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 
-use chip8::Chip8Handle;
-use fuse::FuseHandle;
-use input::InputHandle;
-use vram::{ScreenSize, VRAMHandle};
-
-pub(crate) mod audio;
-pub(crate) mod chip8;
-pub(crate) mod counter;
-pub(crate) mod fuse;
-pub(crate) mod input;
-pub(crate) mod ui;
-pub(crate) mod util;
-pub(crate) mod vram;
+use rusty_chips::audio::{self, AudioBackend, AudioSettings, Waveform};
+use rusty_chips::display::{DisplaySettings, RenderMode, ScaleMode};
+use rusty_chips::palette::{self, Palette, Rgb};
+use rusty_chips::rom::{self, Rom};
+use rusty_chips::ui::{self, FrontendKind, UiOptions};
+use rusty_chips::{
+    cartridge, parse_size, parse_speed, rom_name, test_roms, Config, Emulator, Quirks, RomLibrary,
+    RomSettingsStore,
+};
 
+/// Options left out fall back to config.toml in the config directory
+/// (~/.config/rusty_chips), which is written with the defaults on first run
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// ROM to play: a file, a cartridge from --export-cart, pack.zip/GAME.ch8 out of a zip, or - for stdin
     #[arg(short, long)]
     rom: Option<String>,
-    #[arg(short, long, default_value = "1.76Mhz")]
-    speed: Option<String>,
+    /// CPU speed, e.g. 500Hz or 1.76MHz. Otherwise ROMs with a saved speed use theirs
+    #[arg(short, long, value_parser = parse_speed)]
+    speed: Option<f64>,
+    /// Buzzer waveform: square, sine, triangle or noise
+    #[arg(long)]
+    waveform: Option<Waveform>,
+    /// Buzzer pitch in Hz, 50 - 2000
+    #[arg(long, value_parser = audio::parse_tone)]
+    tone: Option<f32>,
+    /// Buzzer volume, 0.0 - 1.0
+    #[arg(long)]
+    volume: Option<f32>,
+    /// Start with the buzzer muted
+    #[arg(long, overrides_with = "no_mute")]
+    mute: bool,
+    /// Start with the buzzer on, even if config.toml mutes it
+    #[arg(long, overrides_with = "mute")]
+    no_mute: bool,
+    /// Where the buzzer goes: sdl, null, or wav:<path> to record it
+    #[arg(long)]
+    audio: Option<AudioBackend>,
+    /// Colour theme: classic, green, amber, lcd, high-contrast or octo. ROMs with saved colours use theirs
+    #[arg(long)]
+    theme: Option<Palette>,
+    /// Foreground colour as #RRGGBB, overrides the theme
+    #[arg(long, value_parser = palette::parse_color)]
+    fg: Option<Rgb>,
+    /// Background colour as #RRGGBB, overrides the theme
+    #[arg(long, value_parser = palette::parse_color)]
+    bg: Option<Rgb>,
+    /// Anti-flicker mode: direct, phosphor or blend. ROMs with saved display settings use theirs
+    #[arg(long)]
+    render: Option<RenderMode>,
+    /// Frames a pixel takes to fade out in phosphor mode
+    #[arg(long)]
+    phosphor_frames: Option<u8>,
+    /// Starting window size, e.g. 1280x720
+    #[arg(long, value_parser = parse_size)]
+    window_size: Option<(u32, u32)>,
+    /// Start fullscreen, F11 toggles it
+    #[arg(long, overrides_with = "windowed")]
+    fullscreen: bool,
+    /// Start in a window, even if config.toml says fullscreen
+    #[arg(long, overrides_with = "fullscreen")]
+    windowed: bool,
+    /// Screen scaling: integer (crisp, same sized pixels) or fit (as large as possible)
+    #[arg(long)]
+    scale: Option<ScaleMode>,
+    /// Where screenshots are saved, defaults to screenshots/ in the config directory
+    #[arg(long)]
+    screenshot_dir: Option<PathBuf>,
+    /// How to show the emulator: sdl (a window), tui (the terminal) or headless (nothing)
+    #[arg(long)]
+    frontend: Option<FrontendKind>,
+    /// Record the screen to an animated GIF (headless frontend)
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Stop after this many 60Hz frames (headless frontend)
+    #[arg(long)]
+    frames: Option<u64>,
+    /// Pack the ROM, its speed and colours into an Octo cartridge (a GIF) and exit
+    #[arg(long)]
+    export_cart: Option<PathBuf>,
+    /// Reload and restart the ROM whenever its file changes
+    #[arg(long)]
+    watch: bool,
+    /// With --watch, restart from this save state instead of from scratch. F5 saves it (sdl frontend)
+    #[arg(long, value_name = "FILE")]
+    watch_state: Option<PathBuf>,
+}
+
+/// The setting a --flag/--no-flag pair asks for, None if neither was given
+fn flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
 }
 
-fn cli_args() -> (Vec<u8>, f64) {
-    // CLI Arguments
+// --rom value that reads the ROM from stdin
+const STDIN: &str = "-";
+
+fn cli_args() -> (
+    Vec<u8>,
+    f64,
+    Quirks,
+    UiOptions,
+    FrontendKind,
+    Option<PathBuf>,
+) {
+    // CLI Arguments, on top of the config file
     let args = Args::parse();
-    let rom: Vec<u8> = match args.rom.as_deref() {
+    let config = Config::load();
+    let frontend = args.frontend.unwrap_or_else(|| config.frontend());
+    // The terminal frontend draws where stderr usually ends up, keep the log quiet
+    if frontend == FrontendKind::Tui && std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+    let rom_name = match args.rom.as_deref() {
+        Some(STDIN) => String::from("stdin"),
+        Some(path) => rom_name(path),
+        None => String::new(),
+    };
+    let rom = match args.rom.as_deref() {
+        Some(STDIN) => {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .and_then(|_| rom::from_bytes(bytes, None))
+                .unwrap_or_else(|e| {
+                    log::error!("Unable to load a ROM from stdin: {}", e);
+                    std::process::exit(1);
+                })
+        }
         Some(path) => {
-            let mut r = File::open(path).unwrap();
-            let mut v = Vec::new();
-            r.read_to_end(&mut v).unwrap();
-            v
+            let rom = rom::read(Path::new(path)).unwrap_or_else(|e| {
+                log::error!("Unable to load {}: {}", path, e);
+                std::process::exit(1);
+            });
+            let mut library = RomLibrary::load();
+            library.played(Path::new(path));
+            library.save();
+            rom
         }
-        None => {
-            let roms = util::test_roms();
-            roms[0].clone()
+        None => Rom {
+            bytes: test_roms()[0].clone(),
+            options: None,
+        },
+    };
+
+    let rom_sha1 = rom::sha1(&rom.bytes);
+    let mut store = RomSettingsStore::load();
+    // A cartridge's speed, colours and quirks become the ROM's settings
+    if let Some(options) = &rom.options {
+        if store.adopt(&rom_sha1, &rom_name, options.settings()) {
+            log::debug!("Using the settings in cartridge {}", rom_name);
+            store.save();
         }
+    }
+    let rom = rom.bytes;
+    let speed = args.speed.unwrap_or_else(|| config.cpu_speed());
+    let saved = store.get(&rom_sha1, &rom_name);
+    // A speed given on the command line is meant for this run, even over the ROM's own
+    let cpu_speed = match args.speed {
+        Some(speed) => speed,
+        None => saved.speed().unwrap_or(speed),
+    };
+
+    let defaults = config.audio_settings();
+    let audio_settings = AudioSettings {
+        waveform: args.waveform.unwrap_or(defaults.waveform),
+        frequency: args.tone.unwrap_or(defaults.frequency),
+        volume: args.volume.map_or(defaults.volume, |v| v.clamp(0.0, 1.0)),
+        muted: flag(args.mute, args.no_mute).unwrap_or(defaults.muted),
     };
 
-    let cpu_speed: f64 = {
-        if let Some(speed) = args.speed.as_deref() {
-            util::hz_to_secs(speed)
-        } else {
-            // Original COSMAC VIP Frequency
-            util::hz_to_secs("1.76MHz")
+    // Colours from the config file only go with the config file's theme
+    let mut palette = args.theme.unwrap_or_else(|| config.palette());
+    if let Some(fg) = args.fg {
+        palette.set_foreground(fg);
+    }
+    if let Some(bg) = args.bg {
+        palette.set_background(bg);
+    }
+
+    let display = config.display();
+    let options = UiOptions {
+        rom_name,
+        rom_sha1,
+        speed,
+        forced_speed: args.speed,
+        audio_settings,
+        audio_backend: args.audio.unwrap_or_else(|| config.audio_backend()),
+        palette,
+        display: DisplaySettings {
+            mode: args.render.unwrap_or(display.mode),
+            phosphor_frames: args
+                .phosphor_frames
+                .map_or(display.phosphor_frames, |f| f.max(1)),
+        },
+        window_size: args.window_size.unwrap_or_else(|| config.window_size()),
+        fullscreen: flag(args.fullscreen, args.windowed).unwrap_or(config.fullscreen),
+        scale: args.scale.unwrap_or(config.scale),
+        screenshot_dir: args
+            .screenshot_dir
+            .or(config.screenshot_dir.clone())
+            .unwrap_or_else(Config::default_screenshot_dir),
+        frames: args.frames,
+        record: args.record,
+        state_file: args.watch_state,
+    };
+
+    if let Some(path) = &args.export_cart {
+        let palette = saved.palette().unwrap_or_else(|| options.palette.clone());
+        let cart_options =
+            cartridge::Options::new(cpu_speed, &palette, saved.quirks.unwrap_or_default());
+        match cartridge::write(path, &rom, cart_options, &palette) {
+            Ok(_) => log::info!("Saved cartridge {}", path.display()),
+            Err(e) => {
+                log::error!("Unable to save cartridge {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    let watch = match args.rom {
+        Some(path) if args.watch && path != STDIN => Some(PathBuf::from(path)),
+        _ if args.watch => {
+            log::warn!("--watch needs a ROM file, not watching");
+            None
         }
+        _ => None,
     };
 
-    (rom, cpu_speed)
+    let quirks = saved.quirks.unwrap_or_default();
+    (rom, cpu_speed, quirks, options, frontend, watch)
 }
 
 fn main() {
     simple_logger::init_with_env().unwrap();
 
-    let (rom, freq) = cli_args();
+    let (rom, freq, quirks, options, frontend, watch) = cli_args();
 
     let rt = tokio::runtime::Runtime::new().unwrap();
 
     // Comms Channels and async task prep
-    let (video, input, fuse, chip8, audio) = rt.block_on(async {
-        let video = VRAMHandle::new(ScreenSize::S);
-        let input = InputHandle::new();
-        let fuse = FuseHandle::new();
-        let chip8 = Chip8Handle::new(freq, Some(rom), input.clone(), video.clone(), fuse.clone());
-        let audio_timer = chip8.sound_timer.clone();
-        (video, input, fuse, chip8, audio_timer)
-    });
-
-    ui::gui_loop(fuse, input, video, audio, chip8, ScreenSize::S, rt.handle());
+    let emu = rt.block_on(async { Emulator::start(freq, Some(rom)) });
+    rt.block_on(emu.chip8.set_quirks(quirks));
+
+    if let Some(path) = watch {
+        // Still paused, so reloads restart from the machine before the ROM ran
+        rt.block_on(emu.watch(path, options.state_file.clone()));
+    }
+
+    match ui::create(frontend, &emu, options) {
+        Ok(frontend) => ui::run(frontend, &emu, rt.handle()),
+        Err(e) => {
+            log::error!("{}", e);
+            emu.fuse.blow();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::try_parse_from([&["rusty_chips"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn flags_override_the_config_both_ways() {
+        let args = parse(&[]);
+        assert_eq!(flag(args.mute, args.no_mute), None);
+        let args = parse(&["--no-mute"]);
+        assert_eq!(flag(args.mute, args.no_mute), Some(false));
+        // The last one given wins
+        let args = parse(&["--no-mute", "--mute"]);
+        assert_eq!(flag(args.mute, args.no_mute), Some(true));
+        let args = parse(&["--fullscreen", "--windowed"]);
+        assert_eq!(flag(args.fullscreen, args.windowed), Some(false));
+    }
+
+    #[test]
+    fn tone_is_checked() {
+        assert_eq!(parse(&["--tone", "880"]).tone, Some(880.0));
+        assert!(Args::try_parse_from(["rusty_chips", "--tone", "0"]).is_err());
+    }
 }
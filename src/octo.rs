@@ -0,0 +1,900 @@
+// octo.rs: an assembler for Octo, the language cartridges hold their programs in
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//! The CHIP-8 and SUPER-CHIP parts of Octo (https://github.com/JohnEarnest/Octo),
+//! macros and `:calc` included. XO-CHIP instructions, `:stringmode` and `:assert`
+//! are refused with an error.
+use std::collections::HashMap;
+
+const PROGRAM_START: u16 = 0x200;
+const MEMORY_SIZE: u16 = 0x1000;
+// Macros calling themselves would otherwise never finish expanding
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Where an address that wasn't known yet goes once it is
+#[derive(Clone, Copy, Debug)]
+enum Patch {
+    // The low 12 bits of the instruction
+    Instruction,
+    // The two bytes of a :pointer
+    Pointer,
+    // The byte `n` of `v0 := n` in :unpack, which takes the top 4 bits of the address
+    UnpackHigh,
+    // The byte of `v1 := n` in :unpack
+    UnpackLow,
+}
+
+struct Fixup {
+    at: u16,
+    patch: Patch,
+    label: String,
+    line: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// if ... begin, else and end
+struct Branch {
+    // The jump past the block, patched by else or end
+    jump: u16,
+    has_else: bool,
+}
+
+struct Loop {
+    start: u16,
+    // Jumps out of the loop, one for each while
+    exits: Vec<u16>,
+}
+
+struct Assembler {
+    // Still to be read, backwards so the next one pops off the end
+    tokens: Vec<Token>,
+    line: usize,
+    expansions: usize,
+    memory: Vec<u8>,
+    here: u16,
+    // Whether 0x200 still holds the jump to main
+    main_slot: bool,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    branches: Vec<Branch>,
+    loops: Vec<Loop>,
+}
+
+/// Assemble Octo `source` into the bytes to load at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut tokens: Vec<Token> = source
+        .lines()
+        .enumerate()
+        .flat_map(|(n, line)| {
+            let code = line.split('#').next().unwrap_or_default();
+            code.split_whitespace().map(move |text| Token {
+                text: String::from(text),
+                line: n + 1,
+            })
+        })
+        .collect();
+    tokens.reverse();
+    let mut asm = Assembler {
+        tokens,
+        line: 1,
+        expansions: 0,
+        memory: Vec::new(),
+        here: PROGRAM_START,
+        main_slot: true,
+        labels: HashMap::new(),
+        consts: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+    };
+    asm.run().map_err(|e| format!("line {}: {}", asm.line, e))
+}
+
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    } as f64;
+    Some(if negative { -value } else { value })
+}
+
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    match digit.len() {
+        1 => u8::from_str_radix(digit, 16).ok(),
+        _ => None,
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The condition that is true whenever `op` is false
+fn negate(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "==" => "!=",
+        "!=" => "==",
+        "key" => "-key",
+        "-key" => "key",
+        "<" => ">=",
+        ">=" => "<",
+        ">" => "<=",
+        "<=" => ">",
+        _ => return None,
+    })
+}
+
+impl Assembler {
+    fn run(&mut self) -> Result<Vec<u8>, String> {
+        // Room for the jump to main, dropped if main comes first
+        self.instruction(0x10, 0x00)?;
+        while let Some(token) = self.next_token() {
+            self.statement(token)?;
+        }
+        if !self.branches.is_empty() {
+            return Err(String::from("if ... begin without an end"));
+        }
+        if !self.loops.is_empty() {
+            return Err(String::from("loop without an again"));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let address = *self
+                .labels
+                .get(&fixup.label)
+                .ok_or_else(|| format!("'{}' is never defined", fixup.label))?;
+            self.patch(fixup.at, fixup.patch, address);
+        }
+        if self.main_slot {
+            let main = *self
+                .labels
+                .get("main")
+                .ok_or("the program has no main label")?;
+            self.patch(PROGRAM_START, Patch::Instruction, main);
+        }
+        Ok(std::mem::take(&mut self.memory))
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.pop()?;
+        self.line = token.line;
+        Some(token)
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        self.next_token()
+            .map(|token| token.text)
+            .ok_or_else(|| String::from("the program ends part way through a statement"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == text => Ok(()),
+            token => Err(format!("expected '{}', found '{}'", text, token)),
+        }
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= MEMORY_SIZE {
+            return Err(String::from("the program doesn't fit in 4KB"));
+        }
+        let offset = (self.here - PROGRAM_START) as usize;
+        if offset >= self.memory.len() {
+            self.memory.resize(offset + 1, 0);
+        }
+        self.memory[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, high: u8, low: u8) -> Result<(), String> {
+        self.emit(high)?;
+        self.emit(low)
+    }
+
+    fn patch(&mut self, at: u16, patch: Patch, address: u16) {
+        let offset = (at - PROGRAM_START) as usize;
+        let [high, low] = address.to_be_bytes();
+        match patch {
+            Patch::Instruction => {
+                self.memory[offset] = self.memory[offset] & 0xF0 | high & 0x0F;
+                self.memory[offset + 1] = low;
+            }
+            Patch::Pointer => self.memory[offset..offset + 2].copy_from_slice(&[high, low]),
+            Patch::UnpackHigh => self.memory[offset] |= high & 0x0F,
+            Patch::UnpackLow => self.memory[offset] = low,
+        }
+    }
+
+    fn define_label(&mut self, name: String, address: u16) -> Result<(), String> {
+        if !is_name(&name) || register(&name).is_some() {
+            return Err(format!("'{}' can't be a label", name));
+        }
+        if self.labels.insert(name.clone(), address).is_some() {
+            return Err(format!("'{}' is defined twice", name));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), String> {
+        if self.macros.contains_key(&token.text) {
+            return self.expand(token.text);
+        }
+        let text = token.text.as_str();
+        if let Some(x) = self.register(text) {
+            return self.register_statement(x);
+        }
+        if number(text).is_some() || self.consts.contains_key(text) {
+            let byte = self.byte(text)?;
+            return self.emit(byte);
+        }
+        match text {
+            ":" => {
+                let name = self.next()?;
+                if name == "main" && self.main_slot && self.here == PROGRAM_START + 2 {
+                    // Nothing before main, no need to jump to it
+                    self.memory.clear();
+                    self.here = PROGRAM_START;
+                    self.main_slot = false;
+                }
+                self.define_label(name, self.here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = self.value(&value)?;
+                self.define_const(name, value)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let reg = self.next()?;
+                let reg = self
+                    .register(&reg)
+                    .ok_or_else(|| format!("'{}' isn't a register", reg))?;
+                self.aliases.insert(name, reg);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.braces()?;
+                self.define_const(name, value)?;
+            }
+            ":byte" => {
+                let value = self.operand()?;
+                let byte = self.to_byte(value)?;
+                self.emit(byte)?;
+            }
+            ":org" => {
+                let value = self.operand()?;
+                let address = self.to_address(value)?;
+                if address < PROGRAM_START {
+                    return Err(format!(":org {:#X} is below 0x200", address));
+                }
+                self.here = address;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":unpack" => {
+                let nibble = self.next()?;
+                let nibble = self.value(&nibble)? as u8 & 0x0F;
+                let label = self.next()?;
+                let address = self.address(&label, Patch::UnpackHigh, self.here + 1)?;
+                self.instruction(0x60, nibble << 4 | (address >> 8) as u8)?;
+                let address = self.address(&label, Patch::UnpackLow, self.here + 1)?;
+                self.instruction(0x61, address as u8)?;
+            }
+            ":pointer" => {
+                let label = self.next()?;
+                let address = self.address(&label, Patch::Pointer, self.here)?;
+                let [high, low] = address.to_be_bytes();
+                self.instruction(high, low)?;
+            }
+            ":call" => {
+                let label = self.next()?;
+                self.jump(0x2, &label)?;
+            }
+            ":macro" => self.define_macro()?,
+            // Debugger hints, nothing to assemble
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.instruction(0x00, 0xEE)?,
+            "clear" => self.instruction(0x00, 0xE0)?,
+            "exit" => self.instruction(0x00, 0xFD)?,
+            "lores" => self.instruction(0x00, 0xFE)?,
+            "hires" => self.instruction(0x00, 0xFF)?,
+            "scroll-left" => self.instruction(0x00, 0xFC)?,
+            "scroll-right" => self.instruction(0x00, 0xFB)?,
+            "scroll-down" => {
+                let rows = self.next()?;
+                let rows = self.nibble(&rows)?;
+                self.instruction(0x00, 0xC0 | rows)?;
+            }
+            "jump" => {
+                let label = self.next()?;
+                self.jump(0x1, &label)?;
+            }
+            "jump0" => {
+                let label = self.next()?;
+                self.jump(0xB, &label)?;
+            }
+            "native" => {
+                let label = self.next()?;
+                self.jump(0x0, &label)?;
+            }
+            "sprite" => {
+                let x = self.next_register()?;
+                let y = self.next_register()?;
+                let rows = self.next()?;
+                let rows = self.nibble(&rows)?;
+                self.instruction(0xD0 | x, y << 4 | rows)?;
+            }
+            "bcd" => self.register_instruction(0x33)?,
+            "save" => self.register_instruction(0x55)?,
+            "load" => self.register_instruction(0x65)?,
+            "saveflags" => self.register_instruction(0x75)?,
+            "loadflags" => self.register_instruction(0x85)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let op = if text == "delay" { 0x15 } else { 0x18 };
+                self.register_instruction(op)?;
+            }
+            "i" => match self.next()?.as_str() {
+                ":=" => match self.next()?.as_str() {
+                    "hex" => self.register_instruction(0x29)?,
+                    "bighex" => self.register_instruction(0x30)?,
+                    "long" => {
+                        return Err(String::from("i := long is XO-CHIP, which isn't supported"))
+                    }
+                    label => {
+                        let label = String::from(label);
+                        self.jump(0xA, &label)?;
+                    }
+                },
+                "+=" => self.register_instruction(0x1E)?,
+                op => return Err(format!("i {} isn't an instruction", op)),
+            },
+            "if" => {
+                let then = self.condition()?;
+                if !then {
+                    self.branches.push(Branch {
+                        jump: self.here,
+                        has_else: false,
+                    });
+                    self.instruction(0x10, 0x00)?;
+                }
+            }
+            "else" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .filter(|branch| !branch.has_else)
+                    .ok_or("else without an if ... begin")?;
+                self.branches.push(Branch {
+                    jump: self.here,
+                    has_else: true,
+                });
+                self.instruction(0x10, 0x00)?;
+                self.patch(branch.jump, Patch::Instruction, self.here);
+            }
+            "end" => {
+                let branch = self.branches.pop().ok_or("end without an if ... begin")?;
+                self.patch(branch.jump, Patch::Instruction, self.here);
+            }
+            "loop" => self.loops.push(Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(String::from("while outside a loop"));
+                }
+                self.skip_unless(true)?;
+                let exit = self.here;
+                self.loops.last_mut().unwrap().exits.push(exit);
+                self.instruction(0x10, 0x00)?;
+            }
+            "again" => {
+                let lp = self.loops.pop().ok_or("again without a loop")?;
+                let [high, low] = lp.start.to_be_bytes();
+                self.instruction(0x10 | high, low)?;
+                for exit in lp.exits {
+                    self.patch(exit, Patch::Instruction, self.here);
+                }
+            }
+            name if name.starts_with(':') => {
+                return Err(format!("{} isn't supported", name));
+            }
+            // A label on its own calls it
+            label if is_name(label) => {
+                let label = String::from(label);
+                self.jump(0x2, &label)?;
+            }
+            other => return Err(format!("'{}' isn't an instruction", other)),
+        }
+        Ok(())
+    }
+
+    /// vX := ..., vX += ..., etc.
+    fn register_statement(&mut self, x: u8) -> Result<(), String> {
+        let op = self.next()?;
+        let src = self.next()?;
+        if let Some(y) = self.register(&src) {
+            let n = match op.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return Err(format!("v{:X} {} v{:X} isn't an instruction", x, op, y)),
+            };
+            return self.instruction(0x80 | x, y << 4 | n);
+        }
+        match (op.as_str(), src.as_str()) {
+            (":=", "key") => self.instruction(0xF0 | x, 0x0A),
+            (":=", "delay") => self.instruction(0xF0 | x, 0x07),
+            (":=", "random") => {
+                let mask = self.next()?;
+                let mask = self.byte(&mask)?;
+                self.instruction(0xC0 | x, mask)
+            }
+            (":=", value) => {
+                let byte = self.byte(value)?;
+                self.instruction(0x60 | x, byte)
+            }
+            ("+=", value) => {
+                let byte = self.byte(value)?;
+                self.instruction(0x70 | x, byte)
+            }
+            ("-=", value) => {
+                let byte = self.byte(value)?;
+                self.instruction(0x70 | x, byte.wrapping_neg())
+            }
+            _ => Err(format!("v{:X} {} {} isn't an instruction", x, op, src)),
+        }
+    }
+
+    /// FX.. instructions taking a register
+    fn register_instruction(&mut self, op: u8) -> Result<(), String> {
+        let x = self.next_register()?;
+        self.instruction(0xF0 | x, op)
+    }
+
+    /// The instruction `op`NNN with the address of `label`
+    fn jump(&mut self, op: u8, label: &str) -> Result<(), String> {
+        let address = self.address(label, Patch::Instruction, self.here)?;
+        let [high, low] = address.to_be_bytes();
+        self.instruction(op << 4 | high, low)
+    }
+
+    /// The condition of an if, then or begin after it. Returns whether it was then.
+    fn condition(&mut self) -> Result<bool, String> {
+        // The condition is read before knowing which of then and begin follow it
+        let mut condition = Vec::new();
+        while let Some(text) = self.peek() {
+            if text == "then" || text == "begin" {
+                break;
+            }
+            condition.push(self.next_token().unwrap());
+        }
+        let then = self.next()? == "then";
+        condition.reverse();
+        let rest = std::mem::replace(&mut self.tokens, condition);
+        // then: skip the next instruction when the condition is false,
+        // begin: skip the jump past the block when it's true
+        let skipped = self.skip_unless(!then);
+        let leftover = std::mem::replace(&mut self.tokens, rest);
+        skipped?;
+        if let Some(token) = leftover.last() {
+            return Err(format!("unexpected '{}' in the condition", token.text));
+        }
+        Ok(then)
+    }
+
+    /// Read a condition and skip the next instruction when it's false, or when it's
+    /// true if `negated`
+    fn skip_unless(&mut self, negated: bool) -> Result<(), String> {
+        let x = self.next_register()?;
+        let op = self.next()?;
+        let mut op = negate(&op)
+            .map(|_| op.as_str())
+            .ok_or_else(|| format!("'{}' isn't a comparison", op))?;
+        if negated {
+            op = negate(op).unwrap();
+        }
+        match op {
+            "key" => return self.instruction(0xE0 | x, 0xA1),
+            "-key" => return self.instruction(0xE0 | x, 0x9E),
+            _ => {}
+        }
+        let rhs = self.next()?;
+        let rhs = self.register(&rhs).ok_or(rhs);
+        match (op, rhs) {
+            ("==", Ok(y)) => self.instruction(0x90 | x, y << 4),
+            ("!=", Ok(y)) => self.instruction(0x50 | x, y << 4),
+            ("==", Err(n)) => {
+                let byte = self.byte(&n)?;
+                self.instruction(0x40 | x, byte)
+            }
+            ("!=", Err(n)) => {
+                let byte = self.byte(&n)?;
+                self.instruction(0x30 | x, byte)
+            }
+            // The rest compare through VF: vf := rhs, then subtract one from the other
+            // and look at the borrow flag, which is 1 when nothing was borrowed
+            (op, rhs) => {
+                match rhs {
+                    Ok(y) => self.instruction(0x8F, y << 4)?,
+                    Err(n) => {
+                        let byte = self.byte(&n)?;
+                        self.instruction(0x6F, byte)?
+                    }
+                }
+                let (subtract, skip_on) = match op {
+                    // vf -= vx: the flag says vX <= rhs
+                    "<=" => (0x5, 0),
+                    ">" => (0x5, 1),
+                    // vf =- vx: the flag says vX >= rhs
+                    ">=" => (0x7, 0),
+                    _ => (0x7, 1),
+                };
+                self.instruction(0x8F, x << 4 | subtract)?;
+                self.instruction(0x3F, skip_on)
+            }
+        }
+    }
+
+    fn register(&self, text: &str) -> Option<u8> {
+        register(text).or_else(|| self.aliases.get(text).copied())
+    }
+
+    fn next_register(&mut self) -> Result<u8, String> {
+        let text = self.next()?;
+        self.register(&text)
+            .ok_or_else(|| format!("expected a register, found '{}'", text))
+    }
+
+    fn define_const(&mut self, name: String, value: f64) -> Result<(), String> {
+        if !is_name(&name) || register(&name).is_some() {
+            return Err(format!("'{}' can't be a constant", name));
+        }
+        self.consts.insert(name, value);
+        Ok(())
+    }
+
+    /// A number, constant or label defined earlier
+    fn value(&self, text: &str) -> Result<f64, String> {
+        if let Some(n) = number(text) {
+            return Ok(n);
+        }
+        if text == "HERE" {
+            return Ok(self.here as f64);
+        }
+        self.consts
+            .get(text)
+            .copied()
+            .or_else(|| self.labels.get(text).map(|&address| address as f64))
+            .ok_or_else(|| format!("'{}' isn't a number or a constant", text))
+    }
+
+    /// A value, or a :calc expression in braces
+    fn operand(&mut self) -> Result<f64, String> {
+        if self.peek() == Some("{") {
+            return self.braces();
+        }
+        let text = self.next()?;
+        self.value(&text)
+    }
+
+    fn byte(&self, text: &str) -> Result<u8, String> {
+        self.to_byte(self.value(text)?)
+    }
+
+    fn to_byte(&self, value: f64) -> Result<u8, String> {
+        let value = value.floor();
+        if !(-128.0..=255.0).contains(&value) {
+            return Err(format!("{} doesn't fit in a byte", value));
+        }
+        Ok(value as i64 as u8)
+    }
+
+    fn nibble(&self, text: &str) -> Result<u8, String> {
+        match self.value(text)? {
+            n if (0.0..16.0).contains(&n) => Ok(n as u8),
+            n => Err(format!("{} doesn't fit in 4 bits", n)),
+        }
+    }
+
+    fn to_address(&self, value: f64) -> Result<u16, String> {
+        match value.floor() {
+            n if (0.0..MEMORY_SIZE as f64).contains(&n) => Ok(n as u16),
+            n => Err(format!("{} isn't an address", n)),
+        }
+    }
+
+    /// The address `text` stands for, filled in at `at` later if it's a label
+    /// that isn't defined yet
+    fn address(&mut self, text: &str, patch: Patch, at: u16) -> Result<u16, String> {
+        if let Ok(value) = self.value(text) {
+            return self.to_address(value);
+        }
+        if !is_name(text) || self.register(text).is_some() {
+            return Err(format!("'{}' isn't an address", text));
+        }
+        self.fixups.push(Fixup {
+            at,
+            patch,
+            label: String::from(text),
+            line: self.line,
+        });
+        Ok(0)
+    }
+
+    /// The tokens up to the `}` closing the `{` that comes next
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next_token().ok_or("the program ends inside { ... }")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(tokens);
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn braces(&mut self) -> Result<f64, String> {
+        let tokens: Vec<String> = self.block()?.into_iter().map(|t| t.text).collect();
+        let (value, rest) = self.calc(&tokens)?;
+        match rest.first() {
+            Some(token) => Err(format!("unexpected '{}' in the expression", token)),
+            None => Ok(value),
+        }
+    }
+
+    /// A :calc expression. Like Octo, operators have no precedence and are
+    /// applied right to left: `2 * 3 + 1` is 8.
+    fn calc<'t>(&self, tokens: &'t [String]) -> Result<(f64, &'t [String]), String> {
+        let (value, rest) = self.term(tokens)?;
+        let Some((op, after)) = rest.split_first().filter(|(op, _)| *op != ")") else {
+            return Ok((value, rest));
+        };
+        let (rhs, rest) = self.calc(after)?;
+        let (a, b) = (value as i64, rhs as i64);
+        let value = match op.as_str() {
+            "+" => value + rhs,
+            "-" => value - rhs,
+            "*" => value * rhs,
+            "/" => value / rhs,
+            "%" => value % rhs,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << b) as f64,
+            ">>" => (a >> b) as f64,
+            "min" => value.min(rhs),
+            "max" => value.max(rhs),
+            _ => return Err(format!("'{}' isn't an operator", op)),
+        };
+        Ok((value, rest))
+    }
+
+    fn term<'t>(&self, tokens: &'t [String]) -> Result<(f64, &'t [String]), String> {
+        let (first, rest) = tokens
+            .split_first()
+            .ok_or("an expression is missing a value")?;
+        match first.as_str() {
+            "(" => {
+                let (value, rest) = self.calc(rest)?;
+                match rest.split_first() {
+                    Some((close, rest)) if close == ")" => Ok((value, rest)),
+                    _ => Err(String::from("( without a )")),
+                }
+            }
+            "-" => self.term(rest).map(|(v, rest)| (-v, rest)),
+            "~" => self.term(rest).map(|(v, rest)| (!(v as i64) as f64, rest)),
+            "!" => self
+                .term(rest)
+                .map(|(v, rest)| ((v == 0.0) as u8 as f64, rest)),
+            "@" => {
+                let (address, rest) = self.term(rest)?;
+                let offset = (self.to_address(address)? as usize)
+                    .checked_sub(PROGRAM_START as usize)
+                    .ok_or("@ reads from below 0x200")?;
+                Ok((*self.memory.get(offset).unwrap_or(&0) as f64, rest))
+            }
+            text => Ok((self.value(text)?, rest)),
+        }
+    }
+
+    /// :macro name args... { body }
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        while self.peek().is_some_and(|text| text != "{") {
+            args.push(self.next()?);
+        }
+        let body = self.block()?;
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    /// Put a macro's body, with its arguments filled in, in place of the call
+    fn expand(&mut self, name: String) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(format!("{} keeps expanding, does it call itself?", name));
+        }
+        let mut values = HashMap::new();
+        for arg in self.macros[&name].args.clone() {
+            values.insert(arg, self.next()?);
+        }
+        let body: Vec<Token> = self.macros[&name]
+            .body
+            .iter()
+            .rev()
+            .map(|token| Token {
+                text: values.get(&token.text).unwrap_or(&token.text).clone(),
+                line: token.line,
+            })
+            .collect();
+        self.tokens.extend(body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_statements() {
+        let source = "
+            # bounce a ball along the top
+            :const SPEED 2
+            :alias x v1
+            : ball 0b11000000 0b11000000
+            : main
+              clear
+              x := 0
+              i := ball
+              loop
+                sprite x v2 2
+                x += SPEED
+                if x == 60 then x := 0
+                while x != 32
+              again
+              done
+              if v3 key begin
+                v4 := 1
+              else
+                v4 := 2
+              end
+              v5 := random 0xFF
+              loop again
+            : done ;
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x12, 0x04, 0xC0, 0xC0, 0x00, 0xE0, 0x61, 0x00, 0xA2, 0x02, 0xD1, 0x22, 0x71, 0x02,
+                0x41, 0x3C, 0x61, 0x00, 0x41, 0x20, 0x12, 0x18, 0x12, 0x0A, 0x22, 0x28, 0xE3, 0x9E,
+                0x12, 0x22, 0x64, 0x01, 0x12, 0x24, 0x64, 0x02, 0xC5, 0xFF, 0x12, 0x26, 0x00, 0xEE,
+            ]
+        );
+    }
+
+    #[test]
+    fn assembles_directives() {
+        let source = "
+            : main
+              :unpack 0xA data
+              :calc two { 1 + 1 }
+              :macro add-twice reg n { reg += n reg += n }
+              add-twice v3 two
+              if v3 > 5 then v0 := 1
+              :next target
+              v6 := 0
+              :byte { 2 * 3 + 1 }
+              i := target
+              :org 0x218
+            : data 1 2
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x60, 0xA2, 0x61, 0x18, 0x73, 0x02, 0x73, 0x02, 0x6F, 0x05, 0x8F, 0x35, 0x3F, 0x01,
+                0x60, 0x01, 0x66, 0x00, 0x08, 0xA2, 0x11, 0x00, 0x00, 0x00, 0x01, 0x02,
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons_through_vf() {
+        // vf := n, then vf -= vx or vf =- vx, then skip on the borrow flag
+        let cases: [(&str, [u8; 6]); 4] = [
+            ("<=", [0x6F, 0x05, 0x8F, 0x15, 0x3F, 0x00]),
+            (">", [0x6F, 0x05, 0x8F, 0x15, 0x3F, 0x01]),
+            (">=", [0x6F, 0x05, 0x8F, 0x17, 0x3F, 0x00]),
+            ("<", [0x6F, 0x05, 0x8F, 0x17, 0x3F, 0x01]),
+        ];
+        for (op, expected) in cases {
+            let rom = assemble(&format!(": main if v1 {} 5 then", op)).unwrap();
+            assert_eq!(rom, expected, "{}", op);
+        }
+        assert_eq!(assemble(": main if v1 == v2 then").unwrap(), [0x91, 0x20]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert!(assemble(": start\nclear\n")
+            .unwrap_err()
+            .contains("no main"));
+        let err = assemble(": main\n\nmissing\n").unwrap_err();
+        assert!(err.starts_with("line 3:"), "{}", err);
+        assert!(assemble(": main :stringmode").is_err());
+        assert!(assemble(": main i := long 0x1000").is_err());
+        assert!(assemble(": main loop").is_err());
+        assert!(assemble(": main v0 := 256").is_err());
+        assert!(assemble(": main : main").is_err());
+    }
+}
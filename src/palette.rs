@@ -0,0 +1,274 @@
+// palette.rs: display colours and built in themes
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::str::FromStr;
+
+pub type Rgb = [u8; 3];
+
+/// Colours indexed by pixel value: 0 is the background, 1 the foreground.
+/// XO-CHIP's second plane adds 2 (plane 2 only) and 3 (both planes).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<Rgb>,
+}
+
+impl Palette {
+    fn new(name: &str, colors: [Rgb; 4]) -> Self {
+        Self {
+            name: String::from(name),
+            colors: Vec::from(colors),
+        }
+    }
+
+    pub fn classic() -> Self {
+        Self::new(
+            "classic",
+            [
+                [0x00, 0x00, 0x00],
+                [0xFF, 0xFF, 0xFF],
+                [0xAA, 0xAA, 0xAA],
+                [0x55, 0x55, 0x55],
+            ],
+        )
+    }
+
+    pub fn green_phosphor() -> Self {
+        Self::new(
+            "green",
+            [
+                [0x0A, 0x14, 0x0A],
+                [0x33, 0xFF, 0x66],
+                [0x1A, 0x99, 0x3D],
+                [0x99, 0xFF, 0xB3],
+            ],
+        )
+    }
+
+    pub fn amber() -> Self {
+        Self::new(
+            "amber",
+            [
+                [0x14, 0x0C, 0x00],
+                [0xFF, 0xB0, 0x00],
+                [0x99, 0x69, 0x00],
+                [0xFF, 0xD8, 0x80],
+            ],
+        )
+    }
+
+    pub fn lcd() -> Self {
+        Self::new(
+            "lcd",
+            [
+                [0x9B, 0xBC, 0x0F],
+                [0x0F, 0x38, 0x0F],
+                [0x30, 0x62, 0x30],
+                [0x8B, 0xAC, 0x0F],
+            ],
+        )
+    }
+
+    pub fn high_contrast() -> Self {
+        Self::new(
+            "high-contrast",
+            [
+                [0x00, 0x00, 0x00],
+                [0xFF, 0xFF, 0x00],
+                [0x00, 0xFF, 0xFF],
+                [0xFF, 0x00, 0xFF],
+            ],
+        )
+    }
+
+    /// Octo's default colours
+    pub fn octo() -> Self {
+        Self::new(
+            "octo",
+            [
+                [0x99, 0x66, 0x00],
+                [0xFF, 0xCC, 0x00],
+                [0xFF, 0x66, 0x00],
+                [0x66, 0x22, 0x00],
+            ],
+        )
+    }
+
+    pub fn themes() -> Vec<Self> {
+        vec![
+            Self::classic(),
+            Self::green_phosphor(),
+            Self::amber(),
+            Self::lcd(),
+            Self::high_contrast(),
+            Self::octo(),
+        ]
+    }
+
+    /// Colour for a pixel value, values past the end of the palette get the last colour
+    pub fn color(&self, value: usize) -> Rgb {
+        match self.colors.get(value) {
+            Some(c) => *c,
+            None => *self.colors.last().unwrap_or(&[0xFF, 0xFF, 0xFF]),
+        }
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.color(0)
+    }
+
+    pub fn set_background(&mut self, color: Rgb) {
+        self.set(0, color);
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.color(1)
+    }
+
+    pub fn set_foreground(&mut self, color: Rgb) {
+        self.set(1, color);
+    }
+
+    /// A theme by name with its colours overridden, as written in settings files
+    pub fn from_settings(theme: &str, fg: Option<&str>, bg: Option<&str>) -> Result<Self, String> {
+        let mut palette = Self::from_str(theme)?;
+        if let Some(fg) = fg {
+            palette.set_foreground(parse_color(fg)?);
+        }
+        if let Some(bg) = bg {
+            palette.set_background(parse_color(bg)?);
+        }
+        Ok(palette)
+    }
+
+    /// The other way around from `from_settings`: the theme's name,
+    /// plus the foreground and background where they differ from the theme's
+    pub fn to_settings(&self) -> (String, Option<String>, Option<String>) {
+        let theme = Self::from_str(&self.name).unwrap_or_default();
+        let differs = |a: Rgb, b: Rgb| (a != b).then(|| to_hex(a));
+        (
+            theme.name.clone(),
+            differs(self.foreground(), theme.foreground()),
+            differs(self.background(), theme.background()),
+        )
+    }
+
+    fn set(&mut self, value: usize, color: Rgb) {
+        if self.colors.len() <= value {
+            let last = self.color(value);
+            self.colors.resize(value + 1, last);
+        }
+        self.colors[value] = color;
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::classic()
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::themes()
+            .into_iter()
+            .find(|p| p.name.eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<String> = Self::themes().into_iter().map(|p| p.name).collect();
+                format!(
+                    "unknown theme '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// "#RRGGBB" or "RRGGBB"
+pub fn parse_color(s: &str) -> Result<Rgb, String> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("'{}' is not a #RRGGBB colour", s));
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| format!("'{}' is not a #RRGGBB colour", s))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// "#RRGGBB"
+pub fn to_hex(color: Rgb) -> String {
+    format!("#{:02X}{:02X}{:02X}", color[0], color[1], color[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#FFB000"), Ok([0xFF, 0xB0, 0x00]));
+        assert_eq!(parse_color("33ff66"), Ok([0x33, 0xFF, 0x66]));
+        assert!(parse_color("#FFF").is_err());
+        assert!(parse_color("#GGGGGG").is_err());
+        assert!(parse_color("#ÿÿÿ").is_err());
+        assert_eq!(to_hex([0xFF, 0xB0, 0x00]), "#FFB000");
+    }
+
+    #[test]
+    fn themes_by_name() {
+        for theme in Palette::themes() {
+            assert_eq!(Palette::from_str(&theme.name.to_uppercase()), Ok(theme));
+        }
+        assert!(Palette::from_str("purple").is_err());
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let amber = Palette::from_settings("amber", None, None).unwrap();
+        assert_eq!(amber, Palette::amber());
+        assert_eq!(amber.to_settings(), (String::from("amber"), None, None));
+
+        let custom = Palette::from_settings("green", Some("#FFFFFF"), None).unwrap();
+        assert_eq!(custom.foreground(), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(custom.background(), Palette::green_phosphor().background());
+        let (theme, fg, bg) = custom.to_settings();
+        assert_eq!(
+            (theme.as_str(), fg.as_deref(), bg),
+            ("green", Some("#FFFFFF"), None)
+        );
+        assert_eq!(
+            Palette::from_settings(&theme, fg.as_deref(), None),
+            Ok(custom)
+        );
+
+        assert!(Palette::from_settings("classic", Some("white"), None).is_err());
+    }
+
+    #[test]
+    fn short_palettes_repeat_the_last_color() {
+        let mut palette = Palette {
+            name: String::from("classic"),
+            colors: vec![[0; 3]],
+        };
+        assert_eq!(palette.foreground(), [0; 3]);
+        palette.set_foreground([1; 3]);
+        assert_eq!(palette.colors, [[0; 3], [1; 3]]);
+        assert_eq!(palette.color(3), [1; 3]);
+    }
+}
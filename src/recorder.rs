@@ -0,0 +1,212 @@
+// recorder.rs: recording the screen to an animated GIF
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use gif::{Encoder, Repeat};
+use log::debug;
+
+use crate::palette::Palette;
+use crate::vram::{Frame, Memory};
+
+/// Frames are timed against the chip8's 60Hz display
+pub const FRAME_RATE: u64 = 60;
+// Each chip8 pixel becomes SCALE x SCALE GIF pixels
+const SCALE: usize = 4;
+// Most viewers play anything quicker than 2/100s at 10/100s instead
+const MIN_DELAY: u64 = 2;
+
+/// Writes each distinct frame the moment the next one replaces it,
+/// once it is known how many 60Hz ticks it was on screen for
+pub struct Recorder {
+    encoder: Encoder<BufWriter<File>>,
+    path: PathBuf,
+    size: (usize, usize),
+    // The frame on screen: its number, GIF pixels and the tick it appeared on
+    held: Option<(u64, Vec<u8>, u64)>,
+    // Time written out so far in 1/100s, what GIF delays are counted in
+    written: u64,
+    frames: u64,
+}
+
+impl Recorder {
+    /// Start a recording sized for `first`, coloured with `palette`
+    pub fn create(path: &Path, first: &Frame, palette: &Palette) -> io::Result<Self> {
+        let size = first.mem.size();
+        let colors: Vec<u8> = (0..4).flat_map(|value| palette.color(value)).collect();
+        let out = BufWriter::new(File::create(path)?);
+        let mut encoder = Encoder::new(
+            out,
+            (size.0 * SCALE) as u16,
+            (size.1 * SCALE) as u16,
+            &colors,
+        )
+        .map_err(io::Error::other)?;
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(io::Error::other)?;
+        debug!("Recording to {}", path.display());
+        Ok(Self {
+            encoder,
+            path: PathBuf::from(path),
+            size,
+            held: None,
+            written: 0,
+            frames: 0,
+        })
+    }
+
+    /// Feed in the screen as of 60Hz tick `tick`, counted from the start of the recording.
+    /// Nothing is written unless the frame changed.
+    pub fn capture(&mut self, frame: &Frame, tick: u64) -> io::Result<()> {
+        if self
+            .held
+            .as_ref()
+            .is_some_and(|(n, _, _)| *n == frame.number)
+        {
+            return Ok(());
+        }
+        // A GIF can't change size part way through
+        if frame.mem.size() != self.size {
+            return Ok(());
+        }
+        let pixels = self.pixels(&frame.mem);
+        match self.held.take() {
+            // Too short to be shown on its own, the new frame takes its place
+            Some((_, _, start)) if self.delay_until(tick) < MIN_DELAY => {
+                self.held = Some((frame.number, pixels, start));
+            }
+            Some((_, held, _)) => {
+                self.write(&held, tick)?;
+                self.held = Some((frame.number, pixels, tick));
+            }
+            None => self.held = Some((frame.number, pixels, tick)),
+        }
+        Ok(())
+    }
+
+    /// Write the last frame, shown until `tick`, and close the file
+    pub fn finish(mut self, tick: u64) -> io::Result<PathBuf> {
+        if let Some((_, held, start)) = self.held.take() {
+            self.write(&held, tick.max(start + 1))?;
+        }
+        debug!("Recorded {} frames to {}", self.frames, self.path.display());
+        Ok(self.path)
+    }
+
+    fn delay_until(&self, tick: u64) -> u64 {
+        (tick * 100 / FRAME_RATE).saturating_sub(self.written)
+    }
+
+    fn write(&mut self, pixels: &[u8], until: u64) -> io::Result<()> {
+        let delay = self.delay_until(until).max(MIN_DELAY);
+        let frame = gif::Frame {
+            width: (self.size.0 * SCALE) as u16,
+            height: (self.size.1 * SCALE) as u16,
+            delay: delay.min(u16::MAX as u64) as u16,
+            buffer: Cow::Borrowed(pixels),
+            ..gif::Frame::default()
+        };
+        self.encoder.write_frame(&frame).map_err(io::Error::other)?;
+        self.written += delay;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Palette indices for the scaled up screen
+    fn pixels(&self, mem: &Memory) -> Vec<u8> {
+        let (width, height) = self.size;
+        let mut pixels = Vec::with_capacity(width * height * SCALE * SCALE);
+        for y in 0..height * SCALE {
+            for x in 0..width * SCALE {
+                pixels.push(mem[(x / SCALE, y / SCALE)] as u8);
+            }
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::vram::ScreenSize;
+
+    fn frame(number: u64, lit: (usize, usize)) -> Frame {
+        let mut mem = Memory::blank(ScreenSize::S);
+        mem[lit] = true;
+        Frame {
+            number,
+            mem: Arc::new(mem),
+        }
+    }
+
+    /// The delay and the palette index of the top left pixel of each frame written
+    fn record(frames: &[(Frame, u64)], end: u64) -> Vec<(u16, u8)> {
+        let path =
+            std::env::temp_dir().join(format!("rusty_chips_{}_{}.gif", std::process::id(), end));
+        let mut recorder = Recorder::create(&path, &frames[0].0, &Palette::default()).unwrap();
+        for (frame, tick) in frames {
+            recorder.capture(frame, *tick).unwrap();
+        }
+        recorder.finish(end).unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(&path).unwrap()).unwrap();
+        let mut written = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            written.push((frame.delay, frame.buffer[0]));
+        }
+        std::fs::remove_file(&path).unwrap();
+        written
+    }
+
+    #[test]
+    fn frames_last_until_the_next_change() {
+        let frames = [
+            (frame(1, (0, 0)), 0),
+            // Unchanged, nothing to write
+            (frame(1, (0, 0)), 30),
+            (frame(2, (5, 5)), 60),
+        ];
+        assert_eq!(record(&frames, 90), [(100, 1), (50, 0)]);
+    }
+
+    #[test]
+    fn short_frames_are_merged() {
+        let frames = [
+            (frame(1, (0, 0)), 0),
+            (frame(2, (5, 5)), 60),
+            // On screen for 1/60s, shorter than MIN_DELAY, replaces frame 2
+            (frame(3, (0, 0)), 61),
+            (frame(4, (5, 5)), 120),
+        ];
+        assert_eq!(record(&frames, 180), [(100, 1), (100, 1), (100, 0)]);
+    }
+
+    #[test]
+    fn delays_add_up_without_drifting() {
+        // A new frame every 60Hz tick, 1.67/100s each
+        let frames: Vec<_> = (0..6).map(|n| (frame(n, (0, 0)), n)).collect();
+        let delays: Vec<u16> = record(&frames, 6).iter().map(|(d, _)| *d).collect();
+        assert_eq!(delays, [3, 2, 3, 2]);
+        assert_eq!(delays.iter().sum::<u16>(), 10);
+    }
+}
@@ -0,0 +1,276 @@
+// rom.rs: reading chip8 programs
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+use zip::ZipArchive;
+
+use crate::cartridge;
+
+/// Programs are loaded at 0x200, everything above that is theirs
+pub const MAX_ROM_SIZE: usize = 4096 - 0x200;
+// Cartridges are larger than the ROMs in them
+const MAX_ZIPPED_SIZE: u64 = 1024 * 1024;
+// How many zips deep a ROM can be, a zip in a zip is refused
+const MAX_ZIP_DEPTH: usize = 1;
+
+/// File extensions of ROMs, Octo cartridges included
+pub const EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "gif"];
+
+pub fn is_rom_file(path: &Path) -> bool {
+    has_extension(path, &EXTENSIONS)
+}
+
+pub fn is_zip_file(path: &Path) -> bool {
+    has_extension(path, &["zip"])
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.contains(&e.to_lowercase().as_str()))
+}
+
+/// A program read by `read` or `from_bytes`
+#[derive(Clone, Debug)]
+pub struct Rom {
+    pub bytes: Vec<u8>,
+    /// The options a cartridge was saved with. Nothing is done with them here,
+    /// frontends decide whether they become the ROM's settings.
+    pub options: Option<cartridge::Options>,
+}
+
+/// Read a ROM file, refusing anything too large to fit in memory. Octo
+/// cartridges and zip files are unpacked: `pack.zip/GAME.ch8` reads GAME.ch8
+/// out of pack.zip, and `pack.zip` on its own works if it holds one ROM.
+pub fn read(path: &Path) -> io::Result<Rom> {
+    if !path.exists() {
+        if let Some((archive, inner)) = split_zip_path(path) {
+            return from_bytes(fs::read(archive)?, Some(&inner));
+        }
+    }
+    from_bytes(fs::read(path)?, None)
+}
+
+/// The ROM in a file's `bytes`, unpacking cartridges and `inner` (or the only ROM) in zips
+pub fn from_bytes(bytes: Vec<u8>, inner: Option<&str>) -> io::Result<Rom> {
+    unpack(bytes, inner, 0)
+}
+
+/// `from_bytes` for a file found `depth` zips deep
+fn unpack(bytes: Vec<u8>, inner: Option<&str>, depth: usize) -> io::Result<Rom> {
+    if bytes.starts_with(b"PK\x03\x04") {
+        if depth == MAX_ZIP_DEPTH {
+            return Err(invalid_data("zip files inside zip files aren't opened"));
+        }
+        let (_, bytes) = unzip(&bytes, inner)?;
+        return unpack(bytes, None, depth + 1);
+    }
+    let rom = if cartridge::is_cartridge(&bytes) {
+        let (bytes, options) = cartridge::decode(&bytes)?;
+        Rom {
+            bytes,
+            options: Some(options),
+        }
+    } else {
+        Rom {
+            bytes,
+            options: None,
+        }
+    };
+    check_size(&rom.bytes)?;
+    Ok(rom)
+}
+
+/// `pack.zip/GAME.ch8` => (pack.zip, "GAME.ch8"), if pack.zip is a file
+fn split_zip_path(path: &Path) -> Option<(PathBuf, String)> {
+    let archive = path.ancestors().skip(1).find(|a| a.is_file())?;
+    let inner = path.strip_prefix(archive).ok()?;
+    let inner: Vec<_> = inner.iter().map(|part| part.to_string_lossy()).collect();
+    Some((PathBuf::from(archive), inner.join("/")))
+}
+
+fn open_zip(bytes: &[u8]) -> io::Result<ZipArchive<Cursor<&[u8]>>> {
+    ZipArchive::new(Cursor::new(bytes)).map_err(io::Error::other)
+}
+
+/// Names of the ROMs in a zip file
+pub fn zip_roms(bytes: &[u8]) -> io::Result<Vec<String>> {
+    let archive = open_zip(bytes)?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| is_rom_file(Path::new(name)))
+        .map(String::from)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The name and contents of `inner` in a zip file, or of the only ROM in it
+pub fn unzip(bytes: &[u8], inner: Option<&str>) -> io::Result<(String, Vec<u8>)> {
+    let name = match inner {
+        Some(inner) => String::from(inner),
+        None => match &zip_roms(bytes)?[..] {
+            [only] => only.clone(),
+            [] => return Err(invalid_data("no ROMs in the zip file")),
+            names => {
+                return Err(invalid_data(format!(
+                    "the zip file holds {} ROMs, pick one with <zip>/<rom>: {}",
+                    names.len(),
+                    names.join(", ")
+                )))
+            }
+        },
+    };
+    let mut archive = open_zip(bytes)?;
+    let file = archive.by_name(&name).map_err(io::Error::other)?;
+    // Nothing larger could be a ROM or a cartridge, don't unpack all of it
+    let mut contents = Vec::new();
+    file.take(MAX_ZIPPED_SIZE).read_to_end(&mut contents)?;
+    Ok((name, contents))
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub fn check_size(rom: &[u8]) -> io::Result<()> {
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(invalid_data(format!(
+            "{} bytes is too large for a chip8 ROM, the limit is {}",
+            rom.len(),
+            MAX_ROM_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Lower case hex SHA-1 of a ROM, what per ROM settings are keyed by
+pub fn sha1(rom: &[u8]) -> String {
+    Sha1::digest(rom)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn size_cap() {
+        assert!(from_bytes(vec![0; MAX_ROM_SIZE], None).is_ok());
+        let err = from_bytes(vec![0; MAX_ROM_SIZE + 1], None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zip_with_one_rom() {
+        let bytes = zip(&[("readme.txt", b"hi"), ("games/PONG.ch8", &[0x00, 0xE0])]);
+        assert_eq!(zip_roms(&bytes).unwrap(), ["games/PONG.ch8"]);
+        assert_eq!(from_bytes(bytes, None).unwrap().bytes, [0x00, 0xE0]);
+    }
+
+    #[test]
+    fn zip_with_several_roms() {
+        let bytes = zip(&[("b.ch8", &[2]), ("a.c8", &[1])]);
+        assert_eq!(zip_roms(&bytes).unwrap(), ["a.c8", "b.ch8"]);
+        let err = from_bytes(bytes.clone(), None).unwrap_err();
+        assert!(err.to_string().contains("a.c8, b.ch8"));
+        assert_eq!(from_bytes(bytes.clone(), Some("b.ch8")).unwrap().bytes, [2]);
+        assert!(from_bytes(bytes, Some("c.ch8")).is_err());
+    }
+
+    #[test]
+    fn zip_without_roms() {
+        assert!(from_bytes(zip(&[("readme.txt", b"hi")]), None).is_err());
+    }
+
+    #[test]
+    fn zip_in_a_zip() {
+        let inner = zip(&[("PONG.ch8", &[0x00, 0xE0])]);
+        let bytes = zip(&[("inner.ch8", &inner), ("inner.zip", &inner)]);
+        for name in ["inner.ch8", "inner.zip"] {
+            let err = from_bytes(bytes.clone(), Some(name)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("inside zip"));
+        }
+    }
+
+    #[test]
+    fn size_cap_in_zips() {
+        let bytes = zip(&[("BIG.ch8", &[0; MAX_ROM_SIZE + 1])]);
+        assert!(from_bytes(bytes, None).is_err());
+    }
+
+    #[test]
+    fn read_out_of_a_zip_path() {
+        let dir = std::env::temp_dir().join(format!("rusty_chips_rom_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pack = dir.join("pack.zip");
+        fs::write(&pack, zip(&[("games/A.ch8", &[0xA]), ("B.ch8", &[0xB])])).unwrap();
+
+        assert_eq!(read(&pack.join("games/A.ch8")).unwrap().bytes, [0xA]);
+        assert_eq!(read(&pack.join("B.ch8")).unwrap().bytes, [0xB]);
+        assert!(read(&pack).is_err());
+        assert!(read(&pack.join("C.ch8")).is_err());
+        assert!(read(&dir.join("missing.zip/A.ch8")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cartridges_come_with_their_options() {
+        let path = std::env::temp_dir().join(format!("rusty_chips_rom_{}.gif", std::process::id()));
+        let palette = crate::Palette::default();
+        let options = cartridge::Options::new(1.0 / 600.0, &palette, Default::default());
+        cartridge::write(&path, &[0x00, 0xE0], options, &palette).unwrap();
+        let rom = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(rom.bytes, [0x00, 0xE0]);
+        assert_eq!(rom.options.unwrap().tickrate, Some(10));
+        assert!(from_bytes(vec![0x00, 0xE0], None)
+            .unwrap()
+            .options
+            .is_none());
+    }
+
+    #[test]
+    fn sha1_hex() {
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn rom_extensions() {
+        assert!(is_rom_file(Path::new("PONG.CH8")));
+        assert!(is_rom_file(Path::new("cart.gif")));
+        assert!(!is_rom_file(Path::new("pack.zip")));
+        assert!(is_zip_file(Path::new("pack.ZIP")));
+    }
+}
@@ -0,0 +1,262 @@
+// rom_db.rs: titles and recommended settings from the CHIP-8 database
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::chip8::Quirks;
+use crate::recorder::FRAME_RATE;
+use crate::rom_settings::RomSettings;
+use crate::util;
+
+// programs.json from https://github.com/chip-8/chip-8-database, refreshed with
+// data/update-database.sh. A copy in the config directory is used instead.
+const DATABASE_FILE: &str = "programs.json";
+const BUNDLED: &str = include_str!("../data/programs.json");
+
+static DATABASE: OnceLock<RomDatabase> = OnceLock::new();
+
+/// One game or demo, which may have been released as several ROMs
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Program {
+    pub title: String,
+    pub description: Option<String>,
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    // SHA-1 => ROM
+    roms: BTreeMap<String, RomInfo>,
+}
+
+/// One ROM of a program, what it runs on and how it should be set up
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RomInfo {
+    pub file: Option<String>,
+    pub description: Option<String>,
+    // e.g. originalChip8, superchip, xochip
+    pub platforms: Vec<String>,
+    // Instructions per 60Hz frame
+    pub tickrate: Option<u32>,
+    // What the game uses each key for, e.g. up => 5
+    pub keys: BTreeMap<String, serde_json::Value>,
+    pub colors: Option<Colors>,
+    // Platform => quirks that differ from the platform's own
+    pub quirky_platforms: BTreeMap<String, BTreeMap<String, bool>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    // Indexed like Palette::colors, as #RRGGBB
+    pub pixels: Vec<String>,
+}
+
+impl RomInfo {
+    /// The recommended speed and colours as ROM settings
+    pub fn settings(&self) -> RomSettings {
+        let pixels = self.colors.as_ref().map(|c| &c.pixels[..]).unwrap_or(&[]);
+        let (bg, fg) = (pixels.first().cloned(), pixels.get(1).cloned());
+        RomSettings {
+            speed: self
+                .tickrate
+                .map(|ticks| format!("{}Hz", ticks as u64 * FRAME_RATE)),
+            theme: (bg.is_some() || fg.is_some()).then(|| String::from("classic")),
+            fg,
+            bg,
+            quirks: self.quirks(),
+            ..RomSettings::default()
+        }
+    }
+
+    /// The quirks of the CHIP-8 platform the ROM was made for, with the ROM's own
+    /// changes to them. None for ROMs that only run on SUPER-CHIP or XO-CHIP.
+    pub fn quirks(&self) -> Option<Quirks> {
+        let (platform, mut quirks) = self
+            .platforms
+            .iter()
+            .find_map(|p| Some((p, platform_quirks(p)?)))?;
+        for (name, on) in self.quirky_platforms.get(platform).into_iter().flatten() {
+            if !quirks.set(name, *on) {
+                debug!("Ignoring unknown quirk {} for {}", name, platform);
+            }
+        }
+        Some(quirks)
+    }
+
+    /// e.g. "up: 5, fire: A"
+    pub fn key_hints(&self) -> String {
+        let hints: Vec<String> = self
+            .keys
+            .iter()
+            .map(|(action, key)| match key.as_u64() {
+                Some(key) => format!("{}: {:X}", action, key),
+                None => format!("{}: {}", action, key),
+            })
+            .collect();
+        hints.join(", ")
+    }
+}
+
+/// The quirks of a CHIP-8 platform in platforms.json, None for the SUPER-CHIP and
+/// XO-CHIP ones
+fn platform_quirks(platform: &str) -> Option<Quirks> {
+    match platform {
+        "originalChip8" | "hybridVIP" => Some(Quirks {
+            vblank: true,
+            logic: true,
+            ..Quirks::default()
+        }),
+        "modernChip8" => Some(Quirks::default()),
+        _ => None,
+    }
+}
+
+/// The programs in programs.json, looked up by the SHA-1 of their ROMs
+#[derive(Clone, Debug, Default)]
+pub struct RomDatabase {
+    programs: Vec<Program>,
+    // SHA-1 => index into programs
+    by_sha1: HashMap<String, usize>,
+}
+
+impl RomDatabase {
+    fn path() -> Option<PathBuf> {
+        util::config_dir().map(|d| d.join(DATABASE_FILE))
+    }
+
+    /// The database, read the first time it's needed and shared from then on
+    pub fn shared() -> &'static Self {
+        DATABASE.get_or_init(Self::load)
+    }
+
+    /// The database in the config directory, or the one rusty_chips was built with
+    fn load() -> Self {
+        if let Some(Ok(contents)) = Self::path().map(fs::read_to_string) {
+            match serde_json::from_str(&contents) {
+                Ok(programs) => return Self::new(programs),
+                Err(e) => warn!("Ignoring malformed {}: {}", DATABASE_FILE, e),
+            }
+        }
+        Self::bundled()
+    }
+
+    /// The database rusty_chips was built with
+    fn bundled() -> Self {
+        match serde_json::from_str(BUNDLED) {
+            Ok(programs) => Self::new(programs),
+            Err(e) => {
+                warn!("Ignoring the bundled {}: {}", DATABASE_FILE, e);
+                Self::default()
+            }
+        }
+    }
+
+    fn new(programs: Vec<Program>) -> Self {
+        let by_sha1 = programs
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, program)| program.roms.keys().map(move |sha1| (sha1.clone(), idx)))
+            .collect::<HashMap<_, _>>();
+        debug!(
+            "Loaded {} programs, {} ROMs from {}",
+            programs.len(),
+            by_sha1.len(),
+            DATABASE_FILE
+        );
+        Self { programs, by_sha1 }
+    }
+
+    /// The program and ROM with hash `sha1` (see `rom::sha1`)
+    pub fn lookup(&self, sha1: &str) -> Option<(&Program, &RomInfo)> {
+        let program = &self.programs[*self.by_sha1.get(sha1)?];
+        Some((program, program.roms.get(sha1)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "0df2789f661358d8f7370e6cf93490c5bcd44b01";
+
+    #[test]
+    fn bundled_roms_are_found() {
+        // Read separately, so fields the structs miss would show up here
+        let json: serde_json::Value = serde_json::from_str(BUNDLED).unwrap();
+        let db = RomDatabase::bundled();
+        for program in json.as_array().unwrap() {
+            for (sha1, rom) in program["roms"].as_object().unwrap() {
+                let (found, info) = db.lookup(sha1).unwrap();
+                assert_eq!(found.title, program["title"].as_str().unwrap());
+                assert_eq!(info.tickrate.map(u64::from), rom["tickrate"].as_u64());
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_by_sha1() {
+        let json = format!(
+            r##"[{{
+                "title": "Pong",
+                "authors": ["Paul Vervalin"],
+                "roms": {{
+                    "{}": {{
+                        "file": "PONG2.ch8",
+                        "platforms": ["originalChip8"],
+                        "tickrate": 10,
+                        "keys": {{"up": 1, "down": 4}},
+                        "colors": {{"pixels": ["#000000", "#ffffff"]}}
+                    }}
+                }}
+            }}]"##,
+            SHA1
+        );
+        let db = RomDatabase::new(serde_json::from_str(&json).unwrap());
+        let (program, rom) = db.lookup(SHA1).unwrap();
+        assert_eq!(program.title, "Pong");
+        assert_eq!(rom.key_hints(), "down: 4, up: 1");
+        let settings = rom.settings();
+        assert_eq!(settings.speed.as_deref(), Some("600Hz"));
+        assert_eq!(settings.bg.as_deref(), Some("#000000"));
+        assert_eq!(settings.fg.as_deref(), Some("#ffffff"));
+        assert!(db.lookup("0000").is_none());
+    }
+
+    #[test]
+    fn quirks_come_from_the_platform() {
+        let rom = |platforms: &str, quirky: &str| -> RomInfo {
+            serde_json::from_str(&format!(
+                r#"{{"platforms": {}, "quirkyPlatforms": {}}}"#,
+                platforms, quirky
+            ))
+            .unwrap()
+        };
+        let original = rom(r#"["originalChip8"]"#, "{}").quirks().unwrap();
+        assert!(original.vblank && original.logic && !original.shift);
+        let shifty = rom(
+            r#"["superchip", "modernChip8"]"#,
+            r#"{"modernChip8": {"shift": true, "memoryLeaveIUnchanged": true}}"#,
+        );
+        let quirks = shifty.settings().quirks.unwrap();
+        assert!(quirks.shift && quirks.memory_leave_i_unchanged && !quirks.vblank);
+        assert!(rom(r#"["xochip"]"#, "{}").quirks().is_none());
+    }
+}
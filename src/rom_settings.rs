@@ -0,0 +1,152 @@
+// rom_settings.rs: settings remembered for individual ROMs
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::Quirks;
+use crate::display::DisplaySettings;
+use crate::palette::Palette;
+use crate::rom_db::RomDatabase;
+use crate::util;
+
+const ROMS_FILE: &str = "roms.toml";
+
+/// Overrides for one ROM, anything left as None uses the global setting
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RomSettings {
+    // File name the ROM was saved under, so people reading roms.toml can tell entries apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // e.g. "500Hz", spelled like --speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<String>,
+    // Name of a layout in keymaps.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<DisplaySettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<Quirks>,
+}
+
+impl RomSettings {
+    /// Seconds per instruction, if the ROM has its own speed
+    pub fn speed(&self) -> Option<f64> {
+        let speed = self.speed.as_deref()?;
+        util::parse_speed(speed)
+            .map_err(|e| warn!("Ignoring speed in {}: {}", ROMS_FILE, e))
+            .ok()
+    }
+
+    /// The ROM's own colours, if it has them
+    pub fn palette(&self) -> Option<Palette> {
+        let theme = self.theme.as_deref()?;
+        Palette::from_settings(theme, self.fg.as_deref(), self.bg.as_deref())
+            .map_err(|e| warn!("Ignoring colours in {}: {}", ROMS_FILE, e))
+            .ok()
+    }
+
+    pub fn set_palette(&mut self, palette: &Palette) {
+        let (theme, fg, bg) = palette.to_settings();
+        (self.theme, self.fg, self.bg) = (Some(theme), fg, bg);
+    }
+
+    /// These settings, with anything unset taken from `defaults`
+    pub fn or(self, defaults: RomSettings) -> RomSettings {
+        // The colours only make sense together
+        let (theme, fg, bg) = match self.theme {
+            Some(_) => (self.theme, self.fg, self.bg),
+            None => (defaults.theme, defaults.fg, defaults.bg),
+        };
+        RomSettings {
+            name: self.name.or(defaults.name),
+            speed: self.speed.or(defaults.speed),
+            keymap: self.keymap.or(defaults.keymap),
+            theme,
+            fg,
+            bg,
+            display: self.display.or(defaults.display),
+            quirks: self.quirks.or(defaults.quirks),
+        }
+    }
+}
+
+/// Every ROM's overrides, keyed by the SHA-1 of the ROM (see `rom::sha1`).
+/// Older files are keyed by ROM name, those entries are still found by name
+/// and move over to the hash the next time they're saved.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RomSettingsStore {
+    #[serde(flatten)]
+    roms: BTreeMap<String, RomSettings>,
+}
+
+impl RomSettingsStore {
+    pub fn load() -> Self {
+        util::load_toml(ROMS_FILE)
+    }
+
+    pub fn save(&self) {
+        util::save_toml(ROMS_FILE, self);
+    }
+
+    /// Settings for the ROM with hash `sha1`, file name `name`: the ones
+    /// saved for it, then the ones the ROM database recommends
+    pub fn get(&self, sha1: &str, name: &str) -> RomSettings {
+        let recommended = RomDatabase::shared()
+            .lookup(sha1)
+            .map(|(_, rom)| rom.settings())
+            .unwrap_or_default();
+        self.saved(sha1, name).or(recommended)
+    }
+
+    /// Only the settings saved for the ROM
+    pub fn saved(&self, sha1: &str, name: &str) -> RomSettings {
+        self.roms
+            .get(sha1)
+            .or_else(|| self.roms.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Take the settings a cartridge came with as the ROM's own, unless the user
+    /// saved some already. Returns whether they were taken.
+    pub fn adopt(&mut self, sha1: &str, name: &str, settings: RomSettings) -> bool {
+        if self.saved(sha1, name) != RomSettings::default() || settings == RomSettings::default() {
+            return false;
+        }
+        self.set(sha1, name, settings);
+        true
+    }
+
+    pub fn set(&mut self, sha1: &str, name: &str, mut settings: RomSettings) {
+        self.roms.remove(name);
+        settings.name = None;
+        if settings == RomSettings::default() {
+            self.roms.remove(sha1);
+        } else {
+            settings.name = (!name.is_empty()).then(|| String::from(name));
+            self.roms.insert(String::from(sha1), settings);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// screenshot.rs: saving the screen as PNG files and as text
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::display::Persistence;
+use crate::palette::Palette;
+use crate::vram::Memory;
+
+/// An RGB24 image, rows packed with no padding
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// The screen as it is being shown, one image pixel per chip8 pixel
+    pub fn from_screen(persistence: &Persistence, palette: &Palette) -> Self {
+        let (width, height) = persistence.size();
+        let mut rgb = vec![0; width * height * 3];
+        persistence.write_rgb(palette, &mut rgb, width * 3);
+        Self { width, height, rgb }
+    }
+
+    /// Nearest neighbour scaled copy, pixels stay sharp
+    pub fn scaled(&self, width: usize, height: usize) -> Self {
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let sy = y * self.height / height.max(1);
+            for x in 0..width {
+                let sx = x * self.width / width.max(1);
+                let offset = (sy * self.width + sx) * 3;
+                rgb.extend_from_slice(&self.rgb[offset..offset + 3]);
+            }
+        }
+        Self { width, height, rgb }
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// `<dir>/<rom>-<timestamp>.<extension>`, creating the directory if needed
+pub fn capture_path(dir: &Path, rom_name: &str, extension: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    Ok(dir.join(format!(
+        "{}-{}.{}",
+        file_stem(rom_name),
+        timestamp(),
+        extension
+    )))
+}
+
+/// Save the screen at chip8 resolution, plus a copy at the size it is drawn in the window.
+/// Returns the paths written.
+pub fn save(
+    dir: &Path,
+    rom_name: &str,
+    screen: &Image,
+    display_size: (usize, usize),
+) -> io::Result<Vec<PathBuf>> {
+    let native = capture_path(dir, rom_name, "png")?;
+    screen.save_png(&native)?;
+    let mut saved = vec![native.clone()];
+    if display_size != (screen.width, screen.height) {
+        let stem = native
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let scaled = native.with_file_name(format!("{}-scaled.png", stem));
+        screen
+            .scaled(display_size.0, display_size.1)
+            .save_png(&scaled)?;
+        saved.push(scaled);
+    }
+    Ok(saved)
+}
+
+/// The screen as Unicode block art, two chip8 rows per line of text
+pub fn to_text(mem: &Memory) -> String {
+    let (width, height) = mem.size();
+    let mut text = String::with_capacity((width + 1) * height / 2);
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let top = mem[(x, y)];
+            let bottom = y + 1 < height && mem[(x, y + 1)];
+            text.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn file_stem(rom_name: &str) -> &str {
+    let stem = Path::new(rom_name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    if stem.is_empty() {
+        "rusty_chips"
+    } else {
+        stem
+    }
+}
+
+fn timestamp() -> String {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    now.format(format_description!(
+        "[year][month][day]-[hour][minute][second]"
+    ))
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vram::ScreenSize;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn scaling_repeats_pixels() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            rgb: [RED, BLUE].concat(),
+        };
+        let scaled = image.scaled(4, 2);
+        assert_eq!((scaled.width, scaled.height), (4, 2));
+        let row = [RED, RED, BLUE, BLUE].concat();
+        assert_eq!(scaled.rgb, [&row[..], &row[..]].concat());
+        // Shrinking keeps the top left of each block
+        assert_eq!(image.scaled(1, 1).rgb, RED);
+    }
+
+    #[test]
+    fn text_packs_two_rows_per_line() {
+        let mut mem = Memory::blank(ScreenSize::S);
+        mem[(0, 0)] = true;
+        mem[(1, 1)] = true;
+        mem[(2, 0)] = true;
+        mem[(2, 1)] = true;
+        mem[(63, 31)] = true;
+        let text = to_text(&mem);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 16);
+        assert!(lines.iter().all(|line| line.chars().count() == 64));
+        assert!(lines[0].starts_with("▀▄█ "));
+        assert!(lines[15].ends_with(" ▄"));
+    }
+
+    #[test]
+    fn file_names_come_from_the_rom() {
+        assert_eq!(file_stem("PONG2.ch8"), "PONG2");
+        assert_eq!(file_stem(""), "rusty_chips");
+        let path = capture_path(&std::env::temp_dir(), "roms/PONG2.ch8", "png").unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("PONG2-") && name.ends_with(".png"));
+    }
+}
@@ -0,0 +1,111 @@
+// ui/headless.rs: frontend with no window, sound or input
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::thread;
+use std::time::Instant;
+
+use log::{debug, info, warn};
+use tokio::runtime::Handle;
+
+use crate::audio::{AudioBackend, AudioOutput, NullAudio, WavAudio};
+use crate::emulator::Emulator;
+use crate::recorder::Recorder;
+use crate::rom_settings::RomSettingsStore;
+use crate::ui::{Frontend, UiOptions, FRAME};
+use crate::vram::Frame;
+
+/// Runs the chip8 against nothing but a 60Hz frame clock,
+/// optionally for a set number of frames and recording them
+pub struct Headless {
+    frames: Option<u64>,
+    tick: u64,
+    next_frame: Instant,
+    recorder: Option<Recorder>,
+    // There is no sound device, but the buzzer can still be recorded
+    audio_out: Box<dyn AudioOutput>,
+}
+
+impl Headless {
+    pub fn new(emu: &Emulator, options: UiOptions) -> Self {
+        let palette = RomSettingsStore::load()
+            .get(&options.rom_sha1, &options.rom_name)
+            .palette()
+            .unwrap_or(options.palette);
+        let recorder = options.record.as_deref().and_then(|path| {
+            Recorder::create(path, &emu.video.frame(), &palette)
+                .map_err(|e| warn!("Unable to record to {}: {}", path.display(), e))
+                .ok()
+        });
+        let settings = options.audio_settings;
+        let audio_out: Box<dyn AudioOutput> = match &options.audio_backend {
+            AudioBackend::Wav(path) => match WavAudio::create(path, settings, emu.buzzer.clone()) {
+                Ok(wav) => Box::new(wav),
+                Err(e) => {
+                    warn!("Unable to record audio to {}: {}", path.display(), e);
+                    Box::new(NullAudio)
+                }
+            },
+            _ => Box::new(NullAudio),
+        };
+        Self {
+            frames: options.frames,
+            tick: 0,
+            next_frame: Instant::now(),
+            recorder,
+            audio_out,
+        }
+    }
+}
+
+impl Frontend for Headless {
+    /// Nothing to read, just wait for the next frame
+    fn poll_input(&mut self, _emu: &Emulator, _rt: &Handle) -> bool {
+        if self.frames.is_some_and(|frames| self.tick >= frames) {
+            return false;
+        }
+        thread::sleep(self.next_frame.saturating_duration_since(Instant::now()));
+        self.next_frame += FRAME;
+        true
+    }
+
+    fn present(&mut self, frame: &Frame, _changed: bool, _emu: &Emulator, _rt: &Handle) {
+        if let Some(rec) = self.recorder.as_mut() {
+            if let Err(e) = rec.capture(frame, self.tick) {
+                warn!("Unable to record frame: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn drive_audio(&mut self, _emu: &Emulator) {
+        self.audio_out.update();
+    }
+
+    fn end_frame(&mut self) {
+        self.tick += 1;
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(rec) = self.recorder.take() {
+            match rec.finish(self.tick) {
+                Ok(path) => info!("Saved recording {}", path.display()),
+                Err(e) => warn!("Unable to finish recording: {}", e),
+            }
+        }
+        // Finishes a WAV recording
+        self.audio_out = Box::new(NullAudio);
+        debug!("Headless frontend ran {} frames", self.tick);
+    }
+}
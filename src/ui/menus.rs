@@ -13,54 +13,258 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::fs::{read_dir, File};
-use std::io::Read;
-use std::path::PathBuf;
+use std::fs::read_dir;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::string::String;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 use imgui::*;
 
+use crate::audio::{AudioSettings, Waveform, TONE_RANGE};
+use crate::chip8::Quirks;
+use crate::config::Config;
+use crate::display::{DisplaySettings, RenderMode, ScaleMode};
 use crate::fuse::FuseHandle;
+use crate::input::KeypadState;
+use crate::keymap::{KeyMode, Keymap, Keymaps, KEYPAD_LAYOUT};
+use crate::library::RomLibrary;
+use crate::palette::{Palette, Rgb};
+use crate::rom::{self, Rom};
+use crate::rom_db::RomDatabase;
+use crate::rom_settings::{RomSettings, RomSettingsStore};
+use crate::util;
+
+// Larger files aren't read for their hash
+const MAX_HASHED_SIZE: u64 = 1024 * 1024;
+
+/// A file or directory in the Load ROM window
+#[derive(Debug, Clone)]
+struct RomEntry {
+    name: String,
+    path: PathBuf,
+    // Directories and zip files, which are browsed into
+    is_dir: bool,
+    // Unknown for ROMs in zips until they're identified
+    size: Option<u64>,
+    // SHA-1 of the program (see rom::sha1), once identified
+    sha1: Option<String>,
+    // From the ROM database
+    title: Option<String>,
+}
+
+impl RomEntry {
+    /// The file at `path`, from its metadata alone, see `identify`
+    fn new(path: PathBuf) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        let metadata = path.metadata().ok();
+        let is_dir = metadata.as_ref().is_some_and(|m| m.is_dir()) || rom::is_zip_file(&path);
+        Self {
+            name,
+            path,
+            is_dir,
+            size: Some(metadata.as_ref().map_or(0, |m| m.len())),
+            sha1: None,
+            title: None,
+        }
+    }
+
+    /// The ROM `name` in zip file `archive`
+    fn zipped(archive: &Path, name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            path: archive.join(name),
+            is_dir: false,
+            size: None,
+            sha1: None,
+            title: None,
+        }
+    }
+
+    /// Whether this is a ROM that hasn't been identified yet
+    fn unidentified(&self) -> bool {
+        !self.is_dir
+            && self.sha1.is_none()
+            && rom::is_rom_file(&self.path)
+            && self.size.is_none_or(|size| size <= MAX_HASHED_SIZE)
+    }
+
+    /// Hash the ROM, the program for cartridges, and look it up in the database
+    fn identify(&mut self, db: &RomDatabase) {
+        let Ok(rom) = rom::read(&self.path) else {
+            return;
+        };
+        let sha1 = rom::sha1(&rom.bytes);
+        self.title = db.lookup(&sha1).map(|(program, _)| program.title.clone());
+        self.sha1 = Some(sha1);
+        self.size.get_or_insert(rom.bytes.len() as u64);
+    }
+
+    /// Whether `search` (lower case) is in the name or title
+    fn matches(&self, search: &str) -> bool {
+        search.is_empty()
+            || self.name.to_lowercase().contains(search)
+            || self
+                .title
+                .as_ref()
+                .is_some_and(|t| t.to_lowercase().contains(search))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct FSListBox {
-    idx: Arc<RwLock<i32>>,
-    contents: Arc<RwLock<Vec<String>>>,
-    cur_path: Arc<RwLock<String>>,
-    cur_selected: Arc<RwLock<String>>,
-    pub chosen_rom: Arc<RwLock<Vec<u8>>>,
+    cur_path: Arc<RwLock<PathBuf>>,
+    entries: Arc<RwLock<Vec<RomEntry>>>,
+    // Typed in to narrow the list down by name or title
+    search: Arc<RwLock<String>>,
+    // List every file, not only ROMs
+    show_all: Arc<RwLock<bool>>,
+    selected: Arc<RwLock<Option<RomEntry>>>,
+    // The last thing that went wrong, shown in the window
+    error: Arc<RwLock<Option<String>>>,
+    library: Arc<RwLock<RomLibrary>>,
+    // Bumped for every new listing, so identifying the ROMs of an old one stops
+    listing: Arc<AtomicUsize>,
+    pub chosen_rom: Arc<RwLock<Option<Rom>>>,
+    pub chosen_rom_name: Arc<RwLock<String>>,
 }
 
 impl FSListBox {
-    fn new() -> Self {
+    /// Listing `start`, or the working directory if it isn't a directory
+    fn new(start: Option<PathBuf>) -> Self {
+        let start = start
+            .filter(|dir| dir.is_dir())
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_else(|| PathBuf::from("."));
         let new = Self {
-            idx: Arc::new(RwLock::new(0)),
-            contents: Arc::new(RwLock::new(vec![String::from("..")])),
-            cur_path: Arc::new(RwLock::new(String::from(
-                std::env::current_dir().unwrap().to_str().unwrap(), // home::home_dir().unwrap().as_path().to_str().unwrap(),
-            ))),
-            cur_selected: Arc::new(RwLock::new(String::from(""))),
-            chosen_rom: Arc::new(RwLock::new(Vec::new())),
+            cur_path: Arc::new(RwLock::new(start)),
+            entries: Arc::new(RwLock::new(Vec::new())),
+            search: Arc::new(RwLock::new(String::new())),
+            show_all: Arc::new(RwLock::new(false)),
+            selected: Arc::new(RwLock::new(None)),
+            error: Arc::new(RwLock::new(None)),
+            library: Arc::new(RwLock::new(RomLibrary::load())),
+            listing: Arc::new(AtomicUsize::new(0)),
+            chosen_rom: Arc::new(RwLock::new(None)),
+            chosen_rom_name: Arc::new(RwLock::new(String::new())),
         };
         new.update_lists();
         new
     }
 
+    /// Directories first, then files, each sorted by name
     fn update_lists(&self) {
-        let cur_path_read_handle = self.cur_path.as_ref().read().unwrap();
-        let path = PathBuf::from(String::from(&*cur_path_read_handle));
-        let _ = path.join(&*self.cur_selected.as_ref().read().unwrap());
-        let mut d = self.contents.as_ref().write().unwrap();
-        d.clear();
-        d.push(String::from(".."));
-
-        for entry in read_dir(path).unwrap().flatten() {
-            let name = String::from(entry.file_name().to_str().unwrap());
-            d.push(name);
+        let path = self.cur_path.read().unwrap().clone();
+        let show_all = *self.show_all.read().unwrap();
+        let listing = if path.is_dir() {
+            read_dir(&path).map(|dir| {
+                dir.flatten()
+                    .map(|entry| RomEntry::new(entry.path()))
+                    .filter(|entry| entry.is_dir || show_all || rom::is_rom_file(&entry.path))
+                    .collect()
+            })
+        } else {
+            list_zip(&path)
+        };
+        let mut entries = self.entries.write().unwrap();
+        match listing {
+            Ok(listing) => {
+                *entries = listing;
+                *self.error.write().unwrap() = None;
+            }
+            Err(e) => {
+                entries.clear();
+                *self.error.write().unwrap() =
+                    Some(format!("Unable to list {}: {}", path.display(), e))
+            }
         }
-        d.sort();
+        entries.sort_by(|a, b| (!a.is_dir, &a.name).cmp(&(!b.is_dir, &b.name)));
+        drop(entries);
+        self.identify_entries();
     }
+
+    /// Hash the listed ROMs and look them up in the database on another thread,
+    /// so directories full of ROMs list straight away
+    fn identify_entries(&self) {
+        let listing = self.listing.fetch_add(1, Ordering::SeqCst) + 1;
+        let pending: Vec<RomEntry> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.unidentified())
+            .cloned()
+            .collect();
+        if pending.is_empty() {
+            return;
+        }
+        let view = self.clone();
+        std::thread::spawn(move || {
+            let db = RomDatabase::shared();
+            for mut entry in pending {
+                if view.listing.load(Ordering::SeqCst) != listing {
+                    return;
+                }
+                entry.identify(db);
+                let mut entries = view.entries.write().unwrap();
+                if let Some(listed) = entries.iter_mut().find(|e| e.path == entry.path) {
+                    *listed = entry.clone();
+                }
+                drop(entries);
+                let mut selected = view.selected.write().unwrap();
+                if selected.as_ref().is_some_and(|e| e.path == entry.path) {
+                    *selected = Some(entry);
+                }
+            }
+        });
+    }
+
+    /// Show the contents of directory or zip file `dir`
+    pub fn change_dir(&self, dir: &Path) {
+        match dir.canonicalize() {
+            Ok(dir) => {
+                *self.cur_path.write().unwrap() = dir;
+                *self.selected.write().unwrap() = None;
+                self.update_lists();
+            }
+            Err(e) => {
+                *self.error.write().unwrap() =
+                    Some(format!("Unable to open {}: {}", dir.display(), e))
+            }
+        }
+    }
+
+    /// Read a ROM (see rom::read) for the emulator to pick up
+    pub fn open(&self, path: &Path) -> Result<(), String> {
+        let rom =
+            rom::read(path).map_err(|e| format!("Unable to load {}: {}", path.display(), e))?;
+        *self.chosen_rom.write().unwrap() = Some(rom);
+        *self.chosen_rom_name.write().unwrap() = util::rom_name(&path.to_string_lossy());
+        let mut library = self.library.write().unwrap();
+        library.played(path);
+        library.save();
+        Ok(())
+    }
+
+    fn load_selected(&self) {
+        let Some(entry) = self.selected.read().unwrap().clone() else {
+            return;
+        };
+        *self.error.write().unwrap() = self.open(&entry.path).err();
+    }
+}
+
+/// The ROMs in zip file `path`
+fn list_zip(path: &Path) -> io::Result<Vec<RomEntry>> {
+    let bytes = std::fs::read(path)?;
+    Ok(rom::zip_roms(&bytes)?
+        .iter()
+        .map(|name| RomEntry::zipped(path, name))
+        .collect())
 }
 
 // TODO: Remove dead code allowance
@@ -69,9 +273,19 @@ impl FSListBox {
 pub enum MenuWindow {
     Game,
     Config,
+    Keymap,
     None,
 }
 
+/// Something to capture from the screen at the end of the frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Capture {
+    // PNG files in the screenshots directory
+    Screenshot,
+    // Block art on the clipboard
+    Text,
+}
+
 #[derive(Debug, Clone)]
 pub struct MenuState {
     // Whether to show a Config/LoadROM window
@@ -84,25 +298,203 @@ pub struct MenuState {
     pub sub_window_opened: Arc<RwLock<bool>>,
     // we need to send a pause command to
     pub pause_sent: Arc<RwLock<bool>>,
+    // Keypad layouts
+    pub keymaps: Arc<RwLock<Keymaps>>,
+    // Name of the layout currently in use
+    pub active_keymap: Arc<RwLock<String>>,
+    // chip8 key waiting on a key press to be bound to it
+    pub rebinding: Arc<RwLock<Option<u8>>>,
+    // Name and SHA-1 of the running ROM, per game settings are keyed by the hash
+    pub rom_name: Arc<RwLock<String>>,
+    pub rom_sha1: Arc<RwLock<String>>,
+    // CPU speed as typed in, and the one used for ROMs without their own
+    pub speed: Arc<RwLock<String>>,
+    pub default_speed: Arc<RwLock<f64>>,
+    // --speed, used for every ROM instead of its own
+    pub forced_speed: Arc<RwLock<Option<f64>>>,
+    // The speed was changed and still has to be sent to the chip8
+    pub speed_changed: Arc<RwLock<bool>>,
+    // Show the on screen keypad, menu or not
+    pub show_keypad: Arc<RwLock<bool>>,
+    // Latest keypad state from the input actor, for lighting up keys
+    pub keypad_state: Arc<RwLock<KeypadState>>,
+    // Keys held down with the mouse on the on screen keypad
+    pub virtual_keys: Arc<RwLock<[bool; 16]>>,
+    // Buzzer settings, applied to the audio device every frame
+    pub audio_settings: Arc<RwLock<AudioSettings>>,
+    // Display colours, and the ones used for ROMs without their own
+    pub palette: Arc<RwLock<Palette>>,
+    pub default_palette: Arc<RwLock<Palette>>,
+    // Anti-flicker mode in use, and the one used for ROMs without their own
+    pub display: Arc<RwLock<DisplaySettings>>,
+    pub default_display: Arc<RwLock<DisplaySettings>>,
+    // Settings saved for individual ROMs
+    pub rom_settings: Arc<RwLock<RomSettingsStore>>,
+    // How the screen is scaled to the window, and whether the window is fullscreen
+    pub scale_mode: Arc<RwLock<ScaleMode>>,
+    pub fullscreen: Arc<RwLock<bool>>,
+    // Screen capture asked for from the menu or a hotkey
+    pub capture: Arc<RwLock<Option<Capture>>>,
+    // Whether the screen should be being recorded
+    pub recording: Arc<RwLock<bool>>,
+    // Settings saved to config.toml, the startup only ones are edited here directly
+    pub config: Arc<RwLock<Config>>,
 }
 
 impl Default for MenuState {
     fn default() -> Self {
+        let keymaps = Keymaps::load();
+        let active_keymap = keymaps.default.clone();
+        let config = Config::load();
         Self {
             // Init: No open window
             open_window_type: Arc::new(RwLock::new(MenuWindow::None)),
             // Init: see FSListBox for defaults
-            rom_fs_view: Arc::new(FSListBox::new()),
+            rom_fs_view: Arc::new(FSListBox::new(config.rom_dir.clone())),
             // Init: start with menubar closed
             show_menu_bar: Arc::new(RwLock::new(false)),
             // Init: neither Config/LoadROM are open at the start either
             sub_window_opened: Arc::new(RwLock::new(false)),
             // Don't send 'unpause' every frame
             pause_sent: Arc::new(RwLock::new(false)),
+            // Init: built in layouts plus whatever the user saved
+            keymaps: Arc::new(RwLock::new(keymaps)),
+            active_keymap: Arc::new(RwLock::new(active_keymap)),
+            // Init: not waiting on a key press
+            rebinding: Arc::new(RwLock::new(None)),
+            // Init: the built in test ROM
+            rom_name: Arc::new(RwLock::new(String::new())),
+            rom_sha1: Arc::new(RwLock::new(String::new())),
+            // Init: overwritten with the CLI settings
+            speed: Arc::new(RwLock::new(String::from(util::DEFAULT_SPEED))),
+            default_speed: Arc::new(RwLock::new(util::hz_to_secs(util::DEFAULT_SPEED))),
+            forced_speed: Arc::new(RwLock::new(None)),
+            speed_changed: Arc::new(RwLock::new(false)),
+            // Init: keypad hidden, nothing pressed
+            show_keypad: Arc::new(RwLock::new(false)),
+            keypad_state: Arc::new(RwLock::new(KeypadState::default())),
+            virtual_keys: Arc::new(RwLock::new([false; 16])),
+            // Init: overwritten with the CLI settings
+            audio_settings: Arc::new(RwLock::new(AudioSettings::default())),
+            palette: Arc::new(RwLock::new(Palette::default())),
+            default_palette: Arc::new(RwLock::new(Palette::default())),
+            display: Arc::new(RwLock::new(DisplaySettings::default())),
+            default_display: Arc::new(RwLock::new(DisplaySettings::default())),
+            rom_settings: Arc::new(RwLock::new(RomSettingsStore::load())),
+            scale_mode: Arc::new(RwLock::new(ScaleMode::default())),
+            fullscreen: Arc::new(RwLock::new(false)),
+            // Init: nothing to capture
+            capture: Arc::new(RwLock::new(None)),
+            recording: Arc::new(RwLock::new(false)),
+            config: Arc::new(RwLock::new(config)),
         }
     }
 }
 
+impl MenuState {
+    /// Record the running ROM and switch to its saved settings, or the defaults
+    pub fn set_rom(&self, name: &str, sha1: &str) {
+        let saved = self.rom_settings.read().unwrap().get(sha1, name);
+        let keymaps = self.keymaps.read().unwrap();
+        *self.active_keymap.write().unwrap() = keymaps.layout_for(&saved, name);
+        *self.display.write().unwrap() = saved
+            .display
+            .unwrap_or(*self.default_display.read().unwrap());
+        *self.palette.write().unwrap() = saved
+            .palette()
+            .unwrap_or_else(|| self.default_palette.read().unwrap().clone());
+        let speed = self
+            .forced_speed
+            .read()
+            .unwrap()
+            .or(saved.speed())
+            .unwrap_or(*self.default_speed.read().unwrap());
+        *self.speed.write().unwrap() = util::format_speed(speed);
+        *self.speed_changed.write().unwrap() = true;
+        *self.rom_name.write().unwrap() = String::from(name);
+        *self.rom_sha1.write().unwrap() = String::from(sha1);
+    }
+
+    /// Open the Load ROM window on directory or zip file `dir`
+    pub fn browse(&self, dir: &Path) {
+        self.rom_fs_view.change_dir(dir);
+        *self.show_menu_bar.write().unwrap() = true;
+        open_window(self, MenuWindow::Game);
+    }
+
+    /// The settings saved for the running ROM
+    fn saved_settings(&self) -> RomSettings {
+        let name = self.rom_name.read().unwrap();
+        let sha1 = self.rom_sha1.read().unwrap();
+        self.rom_settings.read().unwrap().saved(&sha1, &name)
+    }
+
+    /// How the running ROM expects the chip8 to behave
+    pub fn quirks(&self) -> Quirks {
+        self.own_settings().quirks.unwrap_or_default()
+    }
+
+    /// The running ROM's own settings, saved or from the ROM database
+    fn own_settings(&self) -> RomSettings {
+        let name = self.rom_name.read().unwrap();
+        let sha1 = self.rom_sha1.read().unwrap();
+        self.rom_settings.read().unwrap().get(&sha1, &name)
+    }
+
+    fn save_settings(&self, settings: RomSettings) {
+        let name = self.rom_name.read().unwrap();
+        let sha1 = self.rom_sha1.read().unwrap();
+        let mut store = self.rom_settings.write().unwrap();
+        store.set(&sha1, &name, settings);
+        store.save();
+    }
+
+    /// chip8 key bound to a key in the active layout, given the key's
+    /// physical (scancode) and symbolic (keycode) names
+    pub fn keypad_key(&self, physical: &str, symbolic: &str) -> Option<u8> {
+        let keymaps = self.keymaps.read().unwrap();
+        let active = self.active_keymap.read().unwrap();
+        keymaps
+            .get(&active)
+            .and_then(|k| k.lookup(k.key_name(physical, symbolic)))
+    }
+
+    /// e.g. "QWERTY (physical)"
+    pub fn keymap_label(&self) -> String {
+        let keymaps = self.keymaps.read().unwrap();
+        let active = self.active_keymap.read().unwrap();
+        match keymaps.get(&active) {
+            Some(k) => format!("{} ({})", k.name, k.mode.label()),
+            None => active.clone(),
+        }
+    }
+
+    pub fn is_rebinding(&self) -> bool {
+        self.rebinding.read().unwrap().is_some()
+    }
+
+    /// Bind the pending chip8 key to the pressed key, or cancel the rebind with None.
+    /// Takes the key's (physical, symbolic) names, the active layout decides which is kept
+    pub fn finish_rebind(&self, key_names: Option<(&str, &str)>) {
+        let Some(key) = self.rebinding.write().unwrap().take() else {
+            return;
+        };
+        if let Some((physical, symbolic)) = key_names {
+            let mut keymaps = self.keymaps.write().unwrap();
+            let active = self.active_keymap.read().unwrap();
+            if let Some(layout) = keymaps.get_mut(&active) {
+                let name = String::from(layout.key_name(physical, symbolic));
+                layout.bind(key, &name);
+            }
+        }
+    }
+}
+
+fn open_window(state: &MenuState, window: MenuWindow) {
+    *state.open_window_type.write().unwrap() = window;
+    *state.sub_window_opened.write().unwrap() = true;
+}
+
 /// PLAYYING WITH FIRE (FFI BOUNDRIES)
 pub fn main_menu(ui: &Ui, state: &MenuState, fuse: FuseHandle) {
     // see if imgui closed the sub window and set the current
@@ -123,112 +515,577 @@ pub fn main_menu(ui: &Ui, state: &MenuState, fuse: FuseHandle) {
         ui.set_window_font_scale(2.0);
         ui.menu("ROM", || {
             if ui.menu_item("Load ROM") {
-                let ow_arc = Arc::clone(&state.open_window_type);
-                let mut ow = ow_arc.write().unwrap();
-                *ow = MenuWindow::Game;
-                let swo_arc = Arc::clone(&state.sub_window_opened);
-                let mut swo = swo_arc.write().unwrap();
-                *swo = true;
+                open_window(state, MenuWindow::Game);
+            }
+            if ui.menu_item("Config") {
+                open_window(state, MenuWindow::Config);
             }
+            ui.menu_item_config(format!("Keymap: {}", state.keymap_label()))
+                .enabled(false)
+                .build();
             if ui.menu_item("Exit") {
                 fuse.blow();
             }
             ui.set_window_font_scale(1.0);
         });
+        ui.menu("Input", || {
+            if ui.menu_item("Keymap") {
+                open_window(state, MenuWindow::Keymap);
+            }
+            let mut show_keypad = state.show_keypad.write().unwrap();
+            if ui
+                .menu_item_config("Show Keypad")
+                .selected(*show_keypad)
+                .build()
+            {
+                *show_keypad = !*show_keypad;
+            }
+            ui.set_window_font_scale(1.0);
+        });
+        ui.menu("Capture", || {
+            if ui
+                .menu_item_config("Save Screenshot")
+                .shortcut("F12")
+                .build()
+            {
+                *state.capture.write().unwrap() = Some(Capture::Screenshot);
+            }
+            if ui
+                .menu_item_config("Copy Screenshot as Text")
+                .shortcut("Shift+F12")
+                .build()
+            {
+                *state.capture.write().unwrap() = Some(Capture::Text);
+            }
+            let mut recording = state.recording.write().unwrap();
+            let label = if *recording {
+                "Stop Recording"
+            } else {
+                "Start Recording"
+            };
+            if ui.menu_item_config(label).shortcut("F10").build() {
+                *recording = !*recording;
+            }
+            ui.set_window_font_scale(1.0);
+        });
 
         match &*state.open_window_type.read().unwrap() {
-            MenuWindow::Config => (),
+            MenuWindow::Config => config_window(ui, state),
             MenuWindow::Game => load_rom_window(ui, state),
-            MenuWindow::None => config_window(ui, state),
+            MenuWindow::Keymap => keymap_window(ui, state),
+            MenuWindow::None => (),
         }
     });
 }
 
 /// PLAYYING WITH FIRE (FFI BOUNDRIES)
 fn load_rom_window(ui: &Ui, state: &MenuState) {
-    // Crossing one FFI boundry after another.
-    // Playing it safe with memory.
+    let view = &state.rom_fs_view;
+    let mut load = false;
     let _w = ui
         .window("Load ROM")
         .opened(&mut state.sub_window_opened.write().unwrap())
         .position([50.0, 50.0], Condition::FirstUseEver)
-        .size([300.0, 600.0], Condition::FirstUseEver)
+        .size([640.0, 600.0], Condition::FirstUseEver)
         .build(|| {
-            let arc_path = Arc::clone(&state.rom_fs_view.cur_path);
-            let arc_contents = Arc::clone(&state.rom_fs_view.contents);
-            let arc_idx = Arc::clone(&state.rom_fs_view.idx);
-            let arc_cur_sel = Arc::clone(&state.rom_fs_view.cur_selected);
+            let db = RomDatabase::shared();
+            if let Some(error) = &*view.error.read().unwrap() {
+                ui.text_colored([1.0, 0.4, 0.4, 1.0], error);
+            }
 
-            let _contents = arc_contents.read().unwrap().clone();
-            let contents = _contents
-                .iter()
-                .map(|x| x.as_str())
-                .collect::<Vec<&'_ str>>();
-
-            ui.text(&*arc_path.read().unwrap());
-            if ui.list_box(
-                "##directorylisting",
-                &mut arc_idx.write().unwrap(),
-                &contents,
-                25,
-            ) {
-                let idx = arc_idx.read().unwrap();
-                let mut current_selection = arc_cur_sel.write().unwrap();
-                let p = PathBuf::from(arc_path.read().unwrap().clone())
-                    .join(contents[*idx as usize])
-                    .canonicalize()
-                    .unwrap();
-
-                current_selection.clear();
-                if p.is_dir() {
-                    let mut path = arc_path.write().unwrap();
-                    path.clear();
-                    for c in String::from(p.as_path().to_str().unwrap()).chars() {
-                        path.push(c);
-                    }
-                    drop(path);
-                    drop(current_selection);
-                    drop(idx);
-                    state.rom_fs_view.update_lists()
-                } else {
-                    let sttr = p.file_name().unwrap().to_str().unwrap();
-                    for c in String::from(sttr).chars() {
-                        current_selection.push(c);
+            if let Some(_bar) = ui.tab_bar("##romlists") {
+                if let Some(_tab) = ui.tab_item("Browse") {
+                    load |= browse_tab(ui, view);
+                }
+                if let Some(_tab) = ui.tab_item("Favourites") {
+                    let favourites = view.library.read().unwrap().favourites.clone();
+                    load |= rom_list(ui, view, db, &favourites);
+                }
+                if let Some(_tab) = ui.tab_item("Recent") {
+                    let recent = view.library.read().unwrap().recent.clone();
+                    load |= rom_list(ui, view, db, &recent);
+                }
+            }
+            ui.separator();
+
+            let Some(entry) = view.selected.read().unwrap().clone() else {
+                ui.text_disabled("Double click a ROM to load it");
+                return;
+            };
+            ui.text(&entry.name);
+            ui.same_line();
+            load |= ui.button("Load");
+            ui.same_line();
+            let mut library = view.library.write().unwrap();
+            let label = if library.is_favourite(&entry.path) {
+                "Unfavourite"
+            } else {
+                "Favourite"
+            };
+            if ui.button(label) {
+                library.toggle_favourite(&entry.path);
+                library.save();
+            }
+            drop(library);
+
+            let info = entry.sha1.as_deref().and_then(|sha1| db.lookup(sha1));
+            if let Some((program, info)) = info {
+                ui.text(&program.title);
+                let mut credits = program.authors.join(", ");
+                if let Some(release) = &program.release {
+                    credits = format!("{} ({})", credits, release);
+                }
+                ui.text_disabled(credits);
+                if let Some(description) =
+                    info.description.as_ref().or(program.description.as_ref())
+                {
+                    ui.text_wrapped(description);
+                }
+                if !info.keys.is_empty() {
+                    ui.text_wrapped(format!("Keys: {}", info.key_hints()));
+                }
+            }
+        });
+    if load {
+        view.load_selected();
+    }
+}
+
+/// The current directory, narrowed down by the search box.
+/// true once a ROM is double clicked.
+fn browse_tab(ui: &Ui, view: &FSListBox) -> bool {
+    let cur_path = view.cur_path.read().unwrap().clone();
+    ui.text(cur_path.display().to_string());
+    ui.input_text("Search", &mut view.search.write().unwrap())
+        .hint("name or title")
+        .build();
+    ui.same_line();
+    if ui.checkbox("All files", &mut view.show_all.write().unwrap()) {
+        view.update_lists();
+    }
+
+    let search = view.search.read().unwrap().to_lowercase();
+    let entries = view.entries.read().unwrap().clone();
+    let selected = view
+        .selected
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|e| e.path.clone());
+    let mut load = false;
+    ui.child_window("##listing").size([0.0, -120.0]).build(|| {
+        let columns = ["Name", "Title", "Size", "SHA-1"].map(TableColumnSetup::new);
+        let flags = TableFlags::RESIZABLE | TableFlags::ROW_BG;
+        let Some(_table) = ui.begin_table_header_with_flags("##roms", columns, flags) else {
+            return;
+        };
+        if let Some(parent) = cur_path.parent() {
+            ui.table_next_row();
+            ui.table_next_column();
+            if ui.selectable_config("..").span_all_columns(true).build() {
+                view.change_dir(parent);
+                return;
+            }
+        }
+        for entry in entries.iter().filter(|e| e.matches(&search)) {
+            ui.table_next_row();
+            ui.table_next_column();
+            let label = if entry.is_dir {
+                format!("{}/", entry.name)
+            } else {
+                entry.name.clone()
+            };
+            let clicked = ui
+                .selectable_config(label)
+                .selected(selected.as_ref() == Some(&entry.path))
+                .span_all_columns(true)
+                .allow_double_click(true)
+                .build();
+            ui.table_next_column();
+            ui.text(entry.title.as_deref().unwrap_or_default());
+            if !entry.is_dir {
+                ui.table_next_column();
+                ui.text(entry.size.map(format_size).unwrap_or_default());
+                ui.table_next_column();
+                ui.text(entry.sha1.as_deref().map_or("", |sha1| &sha1[..8]));
+            }
+            if clicked && entry.is_dir {
+                view.change_dir(&entry.path);
+                return;
+            }
+            if clicked {
+                *view.selected.write().unwrap() = Some(entry.clone());
+                load |= ui.is_mouse_double_clicked(MouseButton::Left);
+            }
+        }
+    });
+    load
+}
+
+/// Favourite or recently played ROMs. true once one is double clicked.
+fn rom_list(ui: &Ui, view: &FSListBox, db: &RomDatabase, roms: &[PathBuf]) -> bool {
+    if roms.is_empty() {
+        ui.text_disabled("Nothing here yet");
+        return false;
+    }
+    let selected = view
+        .selected
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|e| e.path.clone());
+    let mut load = false;
+    ui.child_window("##roms").size([0.0, -120.0]).build(|| {
+        for path in roms {
+            let name = path
+                .file_name()
+                .map_or_else(|| path.to_string_lossy(), |n| n.to_string_lossy());
+            let dir = path
+                .parent()
+                .map(|d| d.display().to_string())
+                .unwrap_or_default();
+            let clicked = ui
+                .selectable_config(format!("{}##{}", name, path.display()))
+                .selected(selected.as_ref() == Some(path))
+                .allow_double_click(true)
+                .build();
+            ui.same_line();
+            ui.text_disabled(dir);
+            if clicked {
+                // Only the one picked is identified
+                let mut entry = RomEntry::new(path.clone());
+                if entry.unidentified() {
+                    entry.identify(db);
+                }
+                *view.selected.write().unwrap() = Some(entry);
+                load |= ui.is_mouse_double_clicked(MouseButton::Left);
+            }
+        }
+    });
+    load
+}
+
+/// e.g. "246 B" or "3.5 KiB"
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+/// PLAYYING WITH FIRE (FFI BOUNDRIES)
+fn keymap_window(ui: &Ui, state: &MenuState) {
+    let _w = ui
+        .window("Keymap")
+        .opened(&mut state.sub_window_opened.write().unwrap())
+        .position([50.0, 50.0], Condition::FirstUseEver)
+        .size([560.0, 400.0], Condition::FirstUseEver)
+        .build(|| {
+            let rom_name = state.rom_name.read().unwrap().clone();
+            let mut keymaps = state.keymaps.write().unwrap();
+            let mut active = state.active_keymap.write().unwrap();
+            let mut rebinding = state.rebinding.write().unwrap();
+
+            let names = keymaps.names();
+            let mut idx = names.iter().position(|n| *n == *active).unwrap_or(0);
+            if ui.combo_simple_string("Layout", &mut idx, &names) {
+                *active = names[idx].clone();
+                *rebinding = None;
+            }
+            ui.separator();
+
+            let layout = keymaps.get(&active).cloned().unwrap_or_else(Keymap::qwerty);
+            // Built in layouts stay as they are, edit a copy for this game instead
+            let custom_name = if rom_name.is_empty() {
+                String::from("Custom")
+            } else {
+                rom_name.clone()
+            };
+
+            let mut physical = layout.mode == KeyMode::Physical;
+            if ui.checkbox("Match physical key positions (scancodes)", &mut physical) {
+                if layout.is_builtin() {
+                    keymaps.copy_layout(&active, &custom_name);
+                    *active = custom_name.clone();
+                }
+                if let Some(l) = keymaps.get_mut(&active) {
+                    l.mode = if physical {
+                        KeyMode::Physical
+                    } else {
+                        KeyMode::Symbolic
+                    };
+                }
+                *rebinding = None;
+            }
+            ui.separator();
+
+            for row in KEYPAD_LAYOUT {
+                for (col, key) in row.into_iter().enumerate() {
+                    if col > 0 {
+                        ui.same_line();
                     }
-                    drop(current_selection);
-                    drop(idx);
-                }
-            }
-
-            // "if let Some(_) ..." form is needed by .build(..)
-            #[allow(clippy::redundant_pattern_matching)]
-            if let Some(_) = ui.begin_table("##ListAndLoadFileTable", 2) {
-                let arc_chosen_rom = Arc::clone(&state.rom_fs_view.chosen_rom);
-                ui.table_next_row();
-                ui.table_set_column_index(0);
-                let cursel = arc_cur_sel.read().unwrap();
-                ui.text(&*cursel);
-                drop(cursel);
-                let mut cursel = arc_cur_sel.write().unwrap();
-                if !cursel.is_empty() {
-                    let mut chosen_rom = arc_chosen_rom.as_ref().write().unwrap();
-                    ui.table_set_column_index(1);
-                    if ui.button("Load") {
-                        chosen_rom.clear();
-                        let p = PathBuf::from(&*arc_path.read().unwrap()).join(&*cursel);
-                        match File::open(p) {
-                            Ok(mut file) => {
-                                let _ = file.read_to_end(&mut chosen_rom).unwrap();
-                            }
-                            _ => *cursel = String::from("Unable to load file"),
+                    let bound = if *rebinding == Some(key) {
+                        "..."
+                    } else {
+                        layout.keys[key as usize].as_str()
+                    };
+                    if ui.button_with_size(
+                        format!("{:X}: {}##key{:X}", key, bound, key),
+                        [120.0, 40.0],
+                    ) {
+                        // Built in layouts stay as they are, edit a copy for this game instead
+                        if layout.is_builtin() {
+                            let custom = if rom_name.is_empty() {
+                                String::from("Custom")
+                            } else {
+                                rom_name.clone()
+                            };
+                            keymaps.copy_layout(&active, &custom);
+                            *active = custom;
                         }
+                        *rebinding = Some(key);
                     }
-                    drop(chosen_rom);
                 }
-                drop(cursel);
+            }
+            match *rebinding {
+                Some(key) => ui.text(format!("Press a key for {:X}, Escape to cancel", key)),
+                None => ui.text("Click a key to rebind it"),
+            }
+            let sha1 = state.rom_sha1.read().unwrap();
+            if let Some((program, info)) = RomDatabase::shared().lookup(&sha1) {
+                if !info.keys.is_empty() {
+                    ui.text_wrapped(format!("{} uses {}", program.title, info.key_hints()));
+                }
+            }
+            ui.separator();
+
+            if !rom_name.is_empty() && ui.button(format!("Use for {}", rom_name)) {
+                let mut saved = state.saved_settings();
+                saved.keymap = Some(active.clone());
+                state.save_settings(saved);
+                keymaps.save();
+            }
+            if !rom_name.is_empty() {
+                ui.same_line();
+            }
+            if ui.button("Use as default") {
+                keymaps.default = active.clone();
+                keymaps.save();
             }
         });
 }
 
-/// (WILL BE) PLAYYING WITH FIRE (FFI BOUNDRIES)
-fn config_window(_ui: &Ui, _state: &MenuState) {}
+/// PLAYYING WITH FIRE (FFI BOUNDRIES)
+/// The chip8 keypad: keys light up while held, keys the ROM is checking
+/// get a highlighted border, and keys can be held down with the mouse
+pub fn keypad_window(ui: &Ui, state: &MenuState) {
+    let mut opened = true;
+    ui.window("Keypad")
+        .opened(&mut opened)
+        .position([900.0, 50.0], Condition::FirstUseEver)
+        .always_auto_resize(true)
+        .build(|| {
+            let keypad = *state.keypad_state.read().unwrap();
+            let mut virtual_keys = state.virtual_keys.write().unwrap();
+            for row in KEYPAD_LAYOUT {
+                for (col, key) in row.into_iter().enumerate() {
+                    if col > 0 {
+                        ui.same_line();
+                    }
+                    let k = key as usize;
+                    let lit = keypad.held[k] || virtual_keys[k];
+                    let _button =
+                        lit.then(|| ui.push_style_color(StyleColor::Button, [0.9, 0.6, 0.1, 1.0]));
+                    let _border = keypad.polled[k]
+                        .then(|| ui.push_style_color(StyleColor::Border, [0.2, 0.9, 0.2, 1.0]));
+                    let _border_size =
+                        ui.push_style_var(StyleVar::FrameBorderSize(if keypad.polled[k] {
+                            2.0
+                        } else {
+                            0.0
+                        }));
+                    ui.button_with_size(format!("{:X}##pad{:X}", key, key), [48.0, 48.0]);
+                    virtual_keys[k] = ui.is_item_active();
+                }
+            }
+            ui.text_disabled("Green border: key checked by the ROM");
+        });
+    if !opened {
+        *state.show_keypad.write().unwrap() = false;
+        *state.virtual_keys.write().unwrap() = [false; 16];
+    }
+}
+
+/// PLAYYING WITH FIRE (FFI BOUNDRIES)
+fn config_window(ui: &Ui, state: &MenuState) {
+    let _w = ui
+        .window("Config")
+        .opened(&mut state.sub_window_opened.write().unwrap())
+        .position([50.0, 50.0], Condition::FirstUseEver)
+        .size([400.0, 300.0], Condition::FirstUseEver)
+        .build(|| {
+            let mut audio = state.audio_settings.write().unwrap();
+            ui.text("Buzzer");
+            let waveforms = Waveform::ALL.map(|w| w.to_string());
+            let mut idx = Waveform::ALL
+                .iter()
+                .position(|w| *w == audio.waveform)
+                .unwrap_or(0);
+            if ui.combo_simple_string("Waveform", &mut idx, &waveforms) {
+                audio.waveform = Waveform::ALL[idx];
+            }
+            ui.slider(
+                "Frequency (Hz)",
+                *TONE_RANGE.start(),
+                *TONE_RANGE.end(),
+                &mut audio.frequency,
+            );
+            ui.slider("Volume", 0.0, 1.0, &mut audio.volume);
+            ui.checkbox("Mute", &mut audio.muted);
+            ui.separator();
+
+            let mut palette = state.palette.write().unwrap();
+            ui.text("Display");
+            let themes = Palette::themes();
+            let mut names: Vec<String> = themes.iter().map(|t| t.name.clone()).collect();
+            let mut idx = match themes.iter().position(|t| *t == *palette) {
+                Some(idx) => idx,
+                None => {
+                    names.push(String::from("custom"));
+                    names.len() - 1
+                }
+            };
+            if ui.combo_simple_string("Theme", &mut idx, &names) && idx < themes.len() {
+                *palette = themes[idx].clone();
+            }
+            let mut fg = rgb_to_f32(palette.foreground());
+            if ui.color_edit3("Foreground", &mut fg) {
+                palette.set_foreground(rgb_from_f32(fg));
+            }
+            let mut bg = rgb_to_f32(palette.background());
+            if ui.color_edit3("Background", &mut bg) {
+                palette.set_background(rgb_from_f32(bg));
+            }
+
+            let mut scale = state.scale_mode.write().unwrap();
+            let scales = ScaleMode::ALL.map(|m| m.to_string());
+            let mut idx = ScaleMode::ALL
+                .iter()
+                .position(|m| *m == *scale)
+                .unwrap_or(0);
+            if ui.combo_simple_string("Scaling", &mut idx, &scales) {
+                *scale = ScaleMode::ALL[idx];
+            }
+            ui.checkbox("Fullscreen (F11)", &mut state.fullscreen.write().unwrap());
+
+            let mut display = state.display.write().unwrap();
+            let modes = RenderMode::ALL.map(|m| m.to_string());
+            let mut idx = RenderMode::ALL
+                .iter()
+                .position(|m| *m == display.mode)
+                .unwrap_or(0);
+            if ui.combo_simple_string("Anti-flicker", &mut idx, &modes) {
+                display.mode = RenderMode::ALL[idx];
+            }
+            if display.mode == RenderMode::Phosphor {
+                ui.slider("Fade (frames)", 1, 30, &mut display.phosphor_frames);
+            }
+            ui.separator();
+
+            let mut speed = state.speed.write().unwrap();
+            ui.text("CPU");
+            if ui.input_text("Speed", &mut speed).build() {
+                *state.speed_changed.write().unwrap() = true;
+            }
+            let current_speed_ok = util::parse_speed(&speed).is_ok();
+            if !current_speed_ok {
+                ui.text_colored([1.0, 0.4, 0.4, 1.0], "e.g. 500Hz or 1.76MHz");
+            }
+            ui.separator();
+
+            let rom_name = state.rom_name.read().unwrap().clone();
+            if !rom_name.is_empty() {
+                ui.text(format!(
+                    "Settings above and the keymap, for {} only",
+                    rom_name
+                ));
+                if current_speed_ok && ui.button(format!("Save for {}", rom_name)) {
+                    let mut saved = state.saved_settings();
+                    saved.speed = Some(speed.clone());
+                    saved.keymap = Some(state.active_keymap.read().unwrap().clone());
+                    saved.set_palette(&palette);
+                    saved.display = Some(*display);
+                    state.save_settings(saved);
+                }
+                ui.same_line();
+                if ui.button("Forget") {
+                    state.save_settings(RomSettings::default());
+                }
+                ui.separator();
+            }
+
+            let mut config = state.config.write().unwrap();
+            let mut keymaps = state.keymaps.write().unwrap();
+            ui.text("On startup");
+            ui.input_text("CPU speed", &mut config.speed).build();
+            let speed_ok = util::parse_speed(&config.speed).is_ok();
+            if !speed_ok {
+                ui.text_colored([1.0, 0.4, 0.4, 1.0], "e.g. 500Hz or 1.76MHz");
+            }
+            // Not config.window_size(), that warns about bad values every frame
+            let (w, h) = util::parse_size(&config.window_size).unwrap_or_default();
+            let mut size = [w as i32, h as i32];
+            if ui.input_int2("Window size", &mut size).build() {
+                let [w, h] = size.map(|n| n.max(1));
+                config.window_size = format!("{}x{}", w, h);
+            }
+            let mut rom_dir = config
+                .rom_dir
+                .as_ref()
+                .map(|d| d.display().to_string())
+                .unwrap_or_default();
+            if ui.input_text("ROM directory", &mut rom_dir).build() {
+                config.rom_dir = (!rom_dir.is_empty()).then(|| PathBuf::from(&rom_dir));
+            }
+            if ui.button("Use Load ROM's directory") {
+                config.rom_dir = Some(state.rom_fs_view.cur_path.read().unwrap().clone());
+            }
+            let names = keymaps.names();
+            let mut idx = names
+                .iter()
+                .position(|n| *n == keymaps.default)
+                .unwrap_or(0);
+            if ui.combo_simple_string("Default keymap", &mut idx, &names) {
+                keymaps.default = names[idx].clone();
+            }
+
+            if speed_ok && ui.button("Save Settings") {
+                config.set_audio_settings(*audio);
+                config.scale = *scale;
+                config.fullscreen = *state.fullscreen.read().unwrap();
+                // A ROM's own colours and display settings don't become everyone's
+                let saved = state.own_settings();
+                let mut default_palette = state.default_palette.write().unwrap();
+                if saved.palette().is_none() {
+                    *default_palette = palette.clone();
+                }
+                config.set_palette(&default_palette);
+                let mut default_display = state.default_display.write().unwrap();
+                if saved.display.is_none() {
+                    *default_display = *display;
+                }
+                config.set_display(*default_display);
+                config.save();
+                keymaps.save();
+            }
+        });
+}
+
+fn rgb_to_f32(color: Rgb) -> [f32; 3] {
+    color.map(|c| c as f32 / 255.0)
+}
+
+fn rgb_from_f32(color: [f32; 3]) -> Rgb {
+    color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
@@ -12,9 +12,188 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::debug;
+use tokio::runtime::Handle;
+
+use crate::audio::{AudioBackend, AudioSettings};
+use crate::display::{DisplaySettings, ScaleMode};
+use crate::emulator::Emulator;
+use crate::palette::Palette;
+use crate::recorder::FRAME_RATE;
+use crate::vram::Frame;
+
+pub mod headless;
+#[cfg(feature = "sdl")]
 mod menus;
-mod sdl;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "sdl")]
 mod types;
 
-#[cfg(feature = "sdl")]
-pub use sdl::gui_loop;
+/// One 60Hz chip8 frame, for frontends that pace themselves
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / FRAME_RATE);
+
+/// Frontend settings chosen at startup, everything else comes from the emulator handles
+#[derive(Clone, Debug)]
+pub struct UiOptions {
+    pub rom_name: String,
+    // See rom::sha1, per-ROM settings are saved under it
+    pub rom_sha1: String,
+    // Seconds per instruction for ROMs without a speed of their own
+    pub speed: f64,
+    // --speed, which wins over the ROMs' own
+    pub forced_speed: Option<f64>,
+    pub audio_settings: AudioSettings,
+    pub audio_backend: AudioBackend,
+    pub palette: Palette,
+    pub display: DisplaySettings,
+    pub window_size: (u32, u32),
+    pub fullscreen: bool,
+    pub scale: ScaleMode,
+    pub screenshot_dir: PathBuf,
+    // Headless: stop after this many frames, and record them to a GIF
+    pub frames: Option<u64>,
+    pub record: Option<PathBuf>,
+    // --watch-state, where F5 saves the machine for reloads to restart from
+    pub state_file: Option<PathBuf>,
+}
+
+/// A way of showing the emulator and playing it. `run` calls these in order once a frame.
+pub trait Frontend {
+    /// Handle whatever input arrived since the last frame. false once the user has quit
+    fn poll_input(&mut self, emu: &Emulator, rt: &Handle) -> bool;
+
+    /// Show the screen, `changed` if it is different from the last one presented
+    fn present(&mut self, frame: &Frame, changed: bool, emu: &Emulator, rt: &Handle);
+
+    /// Keep the buzzer going
+    fn drive_audio(&mut self, _emu: &Emulator) {}
+
+    /// Draw menus over the screen and act on them
+    fn show_menus(&mut self, _emu: &Emulator, _rt: &Handle) {}
+
+    /// The frame is done, e.g. swap buffers or flush the terminal
+    fn end_frame(&mut self) {}
+
+    /// The emulator is stopping
+    fn shutdown(&mut self) {}
+}
+
+/// Frontends to pick from with `--frontend`, each behind its own cargo feature
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrontendKind {
+    Sdl,
+    Tui,
+    Headless,
+}
+
+impl FrontendKind {
+    pub const ALL: [FrontendKind; 3] =
+        [FrontendKind::Sdl, FrontendKind::Tui, FrontendKind::Headless];
+
+    /// Whether this build includes it
+    pub fn available(&self) -> bool {
+        match self {
+            FrontendKind::Sdl => cfg!(feature = "sdl"),
+            FrontendKind::Tui => cfg!(feature = "tui"),
+            FrontendKind::Headless => true,
+        }
+    }
+}
+
+/// The first frontend this build includes
+impl Default for FrontendKind {
+    fn default() -> Self {
+        FrontendKind::ALL
+            .into_iter()
+            .find(FrontendKind::available)
+            .unwrap_or(FrontendKind::Headless)
+    }
+}
+
+impl fmt::Display for FrontendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FrontendKind::Sdl => "sdl",
+            FrontendKind::Tui => "tui",
+            FrontendKind::Headless => "headless",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for FrontendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = FrontendKind::ALL
+            .into_iter()
+            .find(|k| k.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown frontend '{}', expected sdl, tui or headless", s))?;
+        if kind.available() {
+            Ok(kind)
+        } else {
+            Err(format!(
+                "rusty_chips was built without the {} frontend, enable the '{}' feature",
+                kind, kind
+            ))
+        }
+    }
+}
+
+/// Set up the chosen frontend
+pub fn create(
+    kind: FrontendKind,
+    emu: &Emulator,
+    options: UiOptions,
+) -> Result<Box<dyn Frontend>, String> {
+    debug!("Starting {} frontend", kind);
+    match kind {
+        #[cfg(feature = "sdl")]
+        FrontendKind::Sdl => Ok(Box::new(sdl::SdlFrontend::new(emu, options))),
+        #[cfg(feature = "tui")]
+        FrontendKind::Tui => tui::TuiFrontend::new(options)
+            .map(|t| Box::new(t) as Box<dyn Frontend>)
+            .map_err(|e| format!("Unable to set up the terminal: {}", e)),
+        FrontendKind::Headless => Ok(Box::new(headless::Headless::new(emu, options))),
+        #[allow(unreachable_patterns)]
+        kind => Err(format!(
+            "rusty_chips was built without the {} frontend",
+            kind
+        )),
+    }
+}
+
+/// Start the chip8 and drive the frontend until it or the emulator stops
+pub fn run(mut frontend: Box<dyn Frontend>, emu: &Emulator, rt: &Handle) {
+    let mut frames = emu.video.subscribe();
+    let mut frame = emu.video.frame();
+    let mut changed = true;
+    rt.block_on(async { emu.chip8.unpause().await });
+
+    while emu.fuse.alive() {
+        if !frontend.poll_input(emu, rt) {
+            break;
+        }
+        if frames.has_changed().unwrap_or(false) {
+            let latest = frames.borrow_and_update().clone();
+            changed |= latest.number != frame.number;
+            frame = latest;
+        }
+        frontend.present(&frame, changed, emu, rt);
+        changed = false;
+        frontend.drive_audio(emu);
+        frontend.show_menus(emu, rt);
+        frontend.end_frame();
+    }
+    frontend.shutdown();
+    emu.fuse.blow();
+    debug!("Frontend stopped");
+}
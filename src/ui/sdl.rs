@@ -13,27 +13,33 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
-use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
 
 use imgui::Context;
 use imgui_glow_renderer::AutoRenderer;
 use imgui_sdl2_support::SdlPlatform;
-use log::debug;
+use log::{debug, info, warn};
 use sdl2::{
-    audio::AudioStatus,
     event::Event,
-    keyboard::Keycode,
-    pixels::Color,
-    video::{GLProfile, Window},
+    keyboard::{Keycode, Mod},
+    pixels::{Color, PixelFormatEnum},
+    render::{Canvas, Texture, TextureCreator},
+    video::{FullscreenType, GLContext, GLProfile, Window, WindowContext},
+    EventPump, Sdl, VideoSubsystem,
 };
+use tokio::runtime::Handle;
 
-use crate::audio::init_sdl_audio;
-use crate::chip8::Chip8Handle;
-use crate::counter::CounterHandle;
-use crate::fuse::FuseHandle;
-use crate::input::InputHandle;
-use crate::ui::{menus, types::PixelPanel};
-use crate::vram::{ScreenSize, VRAMHandle};
+use crate::audio::{AudioBackend, AudioOutput, NullAudio, SdlAudio, WavAudio};
+use crate::display::{DisplaySettings, Persistence};
+use crate::emulator::Emulator;
+use crate::palette::Palette;
+use crate::recorder::{Recorder, FRAME_RATE};
+use crate::screenshot::{self, Image};
+use crate::ui::{menus, menus::Capture, types::PixelPanel, Frontend, UiOptions};
+use crate::vram::Frame;
+use crate::{rom, util};
 
 fn glow_context(window: &Window) -> glow::Context {
     unsafe {
@@ -43,221 +49,515 @@ fn glow_context(window: &Window) -> glow::Context {
     }
 }
 
-pub fn gui_loop(
-    fuse: FuseHandle,
-    input: InputHandle,
-    video: VRAMHandle,
-    sound_timer: CounterHandle,
-    c8: Chip8Handle,
-    screen_size: ScreenSize,
-    rt: &tokio::runtime::Handle,
-) {
-    debug!("Start GUI");
-    // Hardcoded Keys, TODO: Make configurable
-    let keycodes = HashMap::from([
-        (Keycode::Num1, 0x1u8),
-        (Keycode::Num2, 0x2u8),
-        (Keycode::Num3, 0x3u8),
-        (Keycode::Num4, 0xCu8),
-        (Keycode::Q, 0x4u8),
-        (Keycode::W, 0x5u8),
-        (Keycode::E, 0x6u8),
-        (Keycode::R, 0xDu8),
-        (Keycode::A, 0x7u8),
-        (Keycode::S, 0x8u8),
-        (Keycode::D, 0x9u8),
-        (Keycode::F, 0xEu8),
-        (Keycode::Z, 0xAu8),
-        (Keycode::X, 0x0u8),
-        (Keycode::C, 0xBu8),
-        (Keycode::V, 0xFu8),
-    ]);
-
-    // TODO: Make configurable
-    let xf: f32 = 1280.0;
-    let yf: f32 = 720.0;
-    let xu: usize = 1280;
-    let yu: usize = 720;
-    let screen_size_pxu = (xu, yu);
-    let _screen_size_pxf = (xf, yf);
-
-    // TODO: Add SuperChip8 support too!
-    let panel = match screen_size {
-        ScreenSize::L => PixelPanel::new_large(screen_size_pxu.0, screen_size_pxu.1),
-        ScreenSize::S => PixelPanel::new_small(screen_size_pxu.0, screen_size_pxu.1),
-    };
-
-    let sdl_context = sdl2::init().unwrap();
-    let (_, audio_playback) = init_sdl_audio(&sdl_context);
-    let video_sub = sdl_context.video().unwrap();
-    let gl_attr = video_sub.gl_attr();
-    gl_attr.set_context_profile(GLProfile::GLES);
-    gl_attr.set_context_version(3, 2);
-
-    let window = video_sub
-        .window(
-            "Rusty Chips",
-            screen_size_pxu.0 as u32,
-            screen_size_pxu.1 as u32,
-        )
-        .position_centered()
-        .opengl()
-        .build()
-        .unwrap();
-    let gl_context = window.gl_create_context().unwrap();
-    window.gl_make_current(&gl_context).unwrap();
-    window.subsystem().gl_set_swap_interval(1).unwrap();
-
-    let mut canvas = window
-        .into_canvas()
-        .present_vsync()
-        .accelerated()
-        .target_texture()
-        .build()
-        .unwrap();
-
-    let gl = glow_context(canvas.window());
-
-    let mut imgui = Context::create();
-    imgui.set_ini_filename(None);
-    imgui.set_log_filename(None);
-    imgui
-        .fonts()
-        .add_font(&[imgui::FontSource::DefaultFontData { config: None }]);
-    let mut platform = SdlPlatform::init(&mut imgui);
-    let mut renderer = AutoRenderer::initialize(gl, &mut imgui).unwrap();
-
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_draw_color(Color::BLACK);
-    canvas.clear();
-    canvas.present();
-
-    let menu_state = menus::MenuState::default();
-    'running: loop {
-        // Handle input
-        for event in event_pump.poll_iter() {
-            platform.handle_event(&mut imgui, &event);
+/// A window with the screen scaled up in it, imgui menus on top, and SDL audio.
+/// Fields are dropped in order: GL users before the GL context, SDL itself last.
+pub struct SdlFrontend {
+    // Destroyed by hand before the canvas, see Drop
+    texture: Option<Texture>,
+    texture_creator: TextureCreator<WindowContext>,
+    renderer: AutoRenderer,
+    platform: SdlPlatform,
+    imgui: Context,
+    canvas: Canvas<Window>,
+    _gl_context: GLContext,
+    event_pump: EventPump,
+    audio_out: Box<dyn AudioOutput>,
+    video_sub: VideoSubsystem,
+    _sdl: Sdl,
+    menu_state: menus::MenuState,
+    options: UiOptions,
+    persistence: Persistence,
+    panel: PixelPanel,
+    // Latest frame, for screenshots and recordings
+    frame: Frame,
+    shown_palette: Option<Palette>,
+    shown_display: Option<DisplaySettings>,
+    fullscreen: bool,
+    // GIF being recorded and when it was started
+    recording: Option<(Recorder, Instant)>,
+    // On screen keypad keys held down as of the last frame
+    mouse_keys: [bool; 16],
+}
+
+impl SdlFrontend {
+    pub fn new(emu: &Emulator, options: UiOptions) -> Self {
+        debug!("Start GUI");
+
+        let sdl_context = sdl2::init().unwrap();
+        let audio_settings = options.audio_settings;
+        debug!("Audio output: {}", options.audio_backend);
+        let buzzer = emu.buzzer.clone();
+        let audio_out: Box<dyn AudioOutput> = match &options.audio_backend {
+            AudioBackend::Sdl => Box::new(SdlAudio::new(&sdl_context, audio_settings, buzzer)),
+            AudioBackend::Null => Box::new(NullAudio),
+            AudioBackend::Wav(path) => match WavAudio::create(path, audio_settings, buzzer) {
+                Ok(wav) => Box::new(wav),
+                Err(e) => {
+                    warn!("Unable to record audio to {}: {}", path.display(), e);
+                    Box::new(NullAudio)
+                }
+            },
+        };
+        let video_sub = sdl_context.video().unwrap();
+        let gl_attr = video_sub.gl_attr();
+        gl_attr.set_context_profile(GLProfile::GLES);
+        gl_attr.set_context_version(3, 2);
+
+        let mut window = video_sub
+            .window("Rusty Chips", options.window_size.0, options.window_size.1)
+            .position_centered()
+            .resizable()
+            .opengl()
+            .build()
+            .unwrap();
+        if options.fullscreen {
+            window.set_fullscreen(FullscreenType::Desktop).unwrap_or(());
+        }
+        let gl_context = window.gl_create_context().unwrap();
+        window.gl_make_current(&gl_context).unwrap();
+        window.subsystem().gl_set_swap_interval(1).unwrap();
+
+        let mut canvas = window
+            .into_canvas()
+            .present_vsync()
+            .accelerated()
+            .target_texture()
+            .build()
+            .unwrap();
+
+        let gl = glow_context(canvas.window());
+
+        let mut imgui = Context::create();
+        imgui.set_ini_filename(None);
+        imgui.set_log_filename(None);
+        imgui
+            .fonts()
+            .add_font(&[imgui::FontSource::DefaultFontData { config: None }]);
+        let platform = SdlPlatform::init(&mut imgui);
+        let renderer = AutoRenderer::initialize(gl, &mut imgui).unwrap();
+
+        let event_pump = sdl_context.event_pump().unwrap();
+        canvas.set_draw_color(Color::BLACK);
+        canvas.clear();
+        canvas.present();
+
+        let menu_state = menus::MenuState::default();
+        *menu_state.default_display.write().unwrap() = options.display;
+        *menu_state.default_palette.write().unwrap() = options.palette.clone();
+        *menu_state.default_speed.write().unwrap() = options.speed;
+        *menu_state.forced_speed.write().unwrap() = options.forced_speed;
+        menu_state.set_rom(&options.rom_name, &options.rom_sha1);
+        *menu_state.audio_settings.write().unwrap() = audio_settings;
+        *menu_state.scale_mode.write().unwrap() = options.scale;
+        *menu_state.fullscreen.write().unwrap() = options.fullscreen;
+
+        let frame = emu.video.frame();
+        // Laid out for real once the window's size is known
+        let panel = PixelPanel::new(frame.mem.screen_size(), options.window_size, options.scale);
+        // The screen is uploaded to a texture only when something about it changed,
+        // then stretched over the panel in one copy every frame
+        let texture_creator = canvas.texture_creator();
+
+        Self {
+            texture: None,
+            texture_creator,
+            renderer,
+            platform,
+            imgui,
+            canvas,
+            _gl_context: gl_context,
+            event_pump,
+            audio_out,
+            video_sub,
+            _sdl: sdl_context,
+            menu_state,
+            fullscreen: options.fullscreen,
+            options,
+            persistence: Persistence::new(),
+            panel,
+            frame,
+            shown_palette: None,
+            shown_display: None,
+            recording: None,
+            mouse_keys: [false; 16],
+        }
+    }
+
+    fn apply_fullscreen(&mut self) {
+        let wants_fullscreen = *self.menu_state.fullscreen.read().unwrap();
+        if wants_fullscreen != self.fullscreen {
+            let mode = if wants_fullscreen {
+                FullscreenType::Desktop
+            } else {
+                FullscreenType::Off
+            };
+            match self.canvas.window_mut().set_fullscreen(mode) {
+                Ok(_) => self.fullscreen = wants_fullscreen,
+                Err(e) => {
+                    warn!("Unable to change fullscreen mode: {}", e);
+                    *self.menu_state.fullscreen.write().unwrap() = self.fullscreen;
+                }
+            }
+        }
+    }
+
+    fn destroy_texture(&mut self) {
+        if let Some(texture) = self.texture.take() {
+            // Safe while the canvas, its renderer, is alive
+            unsafe { texture.destroy() }
+        }
+    }
+
+    fn update_recording(&mut self, palette: &Palette) {
+        let menu_state = &self.menu_state;
+        let wants_recording = *menu_state.recording.read().unwrap();
+        if wants_recording && self.recording.is_none() {
+            let rom_name = menu_state.rom_name.read().unwrap().clone();
+            let started = screenshot::capture_path(&self.options.screenshot_dir, &rom_name, "gif")
+                .and_then(|path| Recorder::create(&path, &self.frame, palette));
+            match started {
+                Ok(rec) => self.recording = Some((rec, Instant::now())),
+                Err(e) => {
+                    warn!("Unable to start recording: {}", e);
+                    *menu_state.recording.write().unwrap() = false;
+                }
+            }
+        }
+        if let Some((rec, started)) = self.recording.as_mut() {
+            let tick = (started.elapsed().as_secs_f64() * FRAME_RATE as f64) as u64;
+            if let Err(e) = rec.capture(&self.frame, tick) {
+                warn!("Unable to record frame: {}", e);
+                *menu_state.recording.write().unwrap() = false;
+            }
+        }
+        if !*menu_state.recording.read().unwrap() {
+            stop_recording(self.recording.take());
+        }
+    }
+
+    fn take_capture(&mut self, palette: &Palette) {
+        let capture = self.menu_state.capture.write().unwrap().take();
+        match capture {
+            Some(Capture::Screenshot) => {
+                let screen = Image::from_screen(&self.persistence, palette);
+                let area = self.panel.area;
+                let display_size = (area.width() as usize, area.height() as usize);
+                let rom_name = self.menu_state.rom_name.read().unwrap().clone();
+                let dir = &self.options.screenshot_dir;
+                match screenshot::save(dir, &rom_name, &screen, display_size) {
+                    Ok(paths) => {
+                        for path in paths {
+                            info!("Saved screenshot {}", path.display());
+                        }
+                    }
+                    Err(e) => warn!("Unable to save screenshot: {}", e),
+                }
+            }
+            Some(Capture::Text) => {
+                let text = screenshot::to_text(&self.frame.mem);
+                match self.video_sub.clipboard().set_clipboard_text(&text) {
+                    Ok(_) => info!("Copied screenshot to the clipboard"),
+                    Err(e) => warn!("Unable to copy screenshot: {}\n{}", e, text),
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+impl Drop for SdlFrontend {
+    fn drop(&mut self) {
+        self.destroy_texture();
+    }
+}
+
+impl Frontend for SdlFrontend {
+    fn poll_input(&mut self, emu: &Emulator, rt: &Handle) -> bool {
+        let menu_state = &self.menu_state;
+        let input = &emu.input;
+
+        // Forward keys held with the mouse on the on screen keypad
+        let virtual_keys = *menu_state.virtual_keys.read().unwrap();
+        for (key, (now, before)) in virtual_keys.iter().zip(self.mouse_keys.iter()).enumerate() {
+            match (now, before) {
+                (true, false) => rt.block_on(async { input.key_down(key as u8).await }),
+                (false, true) => rt.block_on(async { input.key_up(key as u8).await }),
+                _ => (),
+            }
+        }
+        self.mouse_keys = virtual_keys;
+
+        for event in self.event_pump.poll_iter() {
+            self.platform.handle_event(&mut self.imgui, &event);
 
             match event {
-                Event::Quit { .. } => {
-                    fuse.blow();
-                    break 'running;
+                Event::Quit { .. } => return false,
+                Event::DropFile { filename, .. } => {
+                    let path = Path::new(&filename);
+                    // Zips with a choice of ROMs are opened in the Load ROM window
+                    let choices = fs::read(path)
+                        .ok()
+                        .filter(|_| rom::is_zip_file(path))
+                        .and_then(|bytes| rom::zip_roms(&bytes).ok())
+                        .map_or(0, |roms| roms.len());
+                    if choices > 1 {
+                        menu_state.browse(path);
+                    } else if let Err(e) = menu_state.rom_fs_view.open(path) {
+                        warn!("{}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(key),
+                    scancode: Some(scancode),
+                    repeat: false,
+                    ..
+                } if menu_state.is_rebinding() => {
+                    if key == Keycode::Escape {
+                        menu_state.finish_rebind(None);
+                    } else {
+                        menu_state.finish_rebind(Some((scancode.name(), &key.name())));
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    let mut fullscreen_handle = menu_state.fullscreen.write().unwrap();
+                    *fullscreen_handle = !*fullscreen_handle;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => {
+                    let mut recording_handle = menu_state.recording.write().unwrap();
+                    *recording_handle = !*recording_handle;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(path) = &self.options.state_file {
+                        let state = rt.block_on(emu.chip8.save_state());
+                        match fs::write(path, state.to_bytes()) {
+                            Ok(_) => info!("Saved state to {}", path.display()),
+                            Err(e) => warn!("Unable to write {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    let capture = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        Capture::Text
+                    } else {
+                        Capture::Screenshot
+                    };
+                    *menu_state.capture.write().unwrap() = Some(capture);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => {
                     // draw menu
-                    rt.block_on(async { c8.toggle_exec().await });
+                    rt.block_on(async { emu.chip8.toggle_exec().await });
                     let mut show_menu_bar_handle = menu_state.show_menu_bar.write().unwrap();
                     *show_menu_bar_handle = !*show_menu_bar_handle;
                 }
                 Event::KeyDown {
-                    keycode: Some(key), ..
+                    keycode: Some(key),
+                    scancode: Some(scancode),
+                    repeat: false,
+                    ..
                 } => {
-                    let key = *keycodes.get(&key).unwrap_or(&255u8);
-                    if key != 255u8 {
+                    if let Some(key) = menu_state.keypad_key(scancode.name(), &key.name()) {
                         rt.block_on(async { input.key_down(key).await });
                     }
                 }
                 Event::KeyUp {
-                    keycode: Some(key), ..
+                    keycode: Some(key),
+                    scancode: Some(scancode),
+                    ..
                 } => {
-                    let key = *keycodes.get(&key).unwrap_or(&255u8);
-                    if key != 255u8 {
+                    if let Some(key) = menu_state.keypad_key(scancode.name(), &key.name()) {
                         rt.block_on(async { input.key_up(key).await });
                     }
                 }
                 _ => (),
             }
         }
+        true
+    }
+
+    fn present(&mut self, frame: &Frame, changed: bool, _emu: &Emulator, _rt: &Handle) {
+        self.apply_fullscreen();
+        self.frame = frame.clone();
+        let mut dirty = changed;
+
+        let window_size = self
+            .canvas
+            .output_size()
+            .unwrap_or(self.options.window_size);
+        let scale = *self.menu_state.scale_mode.read().unwrap();
+        let screen_size = frame.mem.screen_size();
+        if self.panel.is_stale(screen_size, window_size, scale) {
+            self.panel = PixelPanel::new(screen_size, window_size, scale);
+        }
+        let texture_size = self.texture.as_ref().map(|t| {
+            let q = t.query();
+            (q.width as usize, q.height as usize)
+        });
+        if texture_size != Some((self.panel.width, self.panel.height)) {
+            self.destroy_texture();
+            self.texture = self
+                .texture_creator
+                .create_texture_streaming(
+                    PixelFormatEnum::RGB24,
+                    self.panel.width as u32,
+                    self.panel.height as u32,
+                )
+                .map_err(|e| warn!("Unable to create screen texture: {}", e))
+                .ok();
+            dirty = true;
+        }
 
-        // Update Video
-        let vram = rt.block_on(async { video.get().await });
-        for x in 0..panel.width {
-            for y in 0..panel.height {
-                if vram[(x, y)] {
-                    canvas.set_draw_color(sdl2::pixels::Color::WHITE);
-                } else {
-                    canvas.set_draw_color(sdl2::pixels::Color::BLACK);
+        let palette = self.menu_state.palette.read().unwrap().clone();
+        let display = *self.menu_state.display.read().unwrap();
+        dirty |= self.persistence.is_fading()
+            || self.shown_palette.as_ref() != Some(&palette)
+            || self.shown_display != Some(display);
+        if dirty {
+            self.persistence.update(&frame.mem, display);
+            if let Some(texture) = self.texture.as_mut() {
+                let persistence = &self.persistence;
+                let upload = texture.with_lock(None, |buf, pitch| {
+                    persistence.write_rgb(&palette, buf, pitch);
+                });
+                if let Err(e) = upload {
+                    warn!("Unable to update screen texture: {}", e);
                 }
-                canvas.fill_rect(panel[(x, y)]).unwrap();
+            }
+            self.shown_palette = Some(palette.clone());
+            self.shown_display = Some(display);
+        }
+
+        // Letterbox
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        if let Some(texture) = self.texture.as_ref() {
+            if let Err(e) = self.canvas.copy(texture, None, self.panel.area) {
+                warn!("Unable to draw screen: {}", e);
             }
         }
+
+        self.update_recording(&palette);
+        self.take_capture(&palette);
+
         unsafe {
-            let _ = sdl2::sys::SDL_RenderFlush(canvas.raw());
+            let _ = sdl2::sys::SDL_RenderFlush(self.canvas.raw());
         }
+    }
 
-        // Update Audio
-        rt.block_on(async {
-            let status = audio_playback.status();
-            let count: u8 = sound_timer.get().await;
-            if count > 0 {
-                match status {
-                    AudioStatus::Paused | AudioStatus::Stopped => {
-                        // Start playback
-                        audio_playback.resume();
-                    }
-                    AudioStatus::Playing => (),
-                }
-            } else {
-                match status {
-                    AudioStatus::Paused | AudioStatus::Stopped => (),
-                    AudioStatus::Playing => {
-                        // Stop playback
-                        audio_playback.pause();
-                    }
-                }
-            }
-        });
+    /// The buzzer itself follows the sound timer, only the settings come from here
+    fn drive_audio(&mut self, _emu: &Emulator) {
+        self.audio_out
+            .set_settings(*self.menu_state.audio_settings.read().unwrap());
+        self.audio_out.update();
+    }
 
-        if *menu_state.show_menu_bar.read().unwrap() {
-            // draw menu
-            platform.prepare_frame(&mut imgui, canvas.window(), &event_pump);
-            let ui = imgui.new_frame();
-            menus::main_menu(ui, &menu_state, fuse.clone());
-            let draw_data = imgui.render();
+    fn show_menus(&mut self, emu: &Emulator, rt: &Handle) {
+        let menu_state = &self.menu_state;
+        if std::mem::take(&mut *menu_state.speed_changed.write().unwrap()) {
+            if let Ok(secs) = util::parse_speed(&menu_state.speed.read().unwrap()) {
+                rt.block_on(emu.chip8.set_speed(secs));
+            }
+        }
+        let show_menu_bar = *menu_state.show_menu_bar.read().unwrap();
+        let show_keypad = *menu_state.show_keypad.read().unwrap();
+        if show_keypad {
+            let keypad = rt.block_on(async { emu.input.keypad().await });
+            *menu_state.keypad_state.write().unwrap() = keypad;
+        }
+        if show_menu_bar || show_keypad {
+            // draw menu and/or keypad
+            self.platform
+                .prepare_frame(&mut self.imgui, self.canvas.window(), &self.event_pump);
+            let ui = self.imgui.new_frame();
+            if show_menu_bar {
+                menus::main_menu(ui, menu_state, emu.fuse.clone());
+            }
+            if show_keypad {
+                menus::keypad_window(ui, menu_state);
+            }
+            let draw_data = self.imgui.render();
 
             // Failures are ok
-            renderer.render(draw_data).unwrap_or(());
-
-            let mut rom_view = menu_state.rom_fs_view.chosen_rom.write().unwrap();
-            if rom_view.len() > 0 {
-                let local_copy_rom = rom_view.clone();
-                rom_view.clear();
-                let mut sub_menu_writer = menu_state.sub_window_opened.write().unwrap();
-                *sub_menu_writer = false;
-                let mut sub_window_writer = menu_state.show_menu_bar.write().unwrap();
-                *sub_window_writer = false;
+            self.renderer.render(draw_data).unwrap_or(());
+        }
 
-                rt.block_on(async {
-                    video.clear_screen().await;
-                    c8.load_rom(local_copy_rom).await;
-                    c8.unpause().await;
-                });
-            } else {
-                drop(rom_view);
+        // Picked in the Load ROM window or dropped on the window
+        let chosen_rom = menu_state.rom_fs_view.chosen_rom.write().unwrap().take();
+        if let Some(chosen_rom) = chosen_rom {
+            let rom_name = menu_state
+                .rom_fs_view
+                .chosen_rom_name
+                .read()
+                .unwrap()
+                .clone();
+            let rom_sha1 = rom::sha1(&chosen_rom.bytes);
+            // A cartridge's speed, colours and quirks become the ROM's settings
+            if let Some(options) = &chosen_rom.options {
+                let mut store = menu_state.rom_settings.write().unwrap();
+                if store.adopt(&rom_sha1, &rom_name, options.settings()) {
+                    store.save();
+                }
             }
-        } else {
+            menu_state.set_rom(&rom_name, &rom_sha1);
+            *menu_state.sub_window_opened.write().unwrap() = false;
+            *menu_state.show_menu_bar.write().unwrap() = false;
+
+            let quirks = menu_state.quirks();
+            rt.block_on(async {
+                emu.video.clear_screen().await;
+                emu.chip8.set_quirks(quirks).await;
+                emu.chip8.load_rom(chosen_rom.bytes).await;
+                emu.chip8.unpause().await;
+            });
+        } else if !show_menu_bar {
             // We need the menu state to know we have notified the Chip8 to start executing again
             // First grab a write handle, we may need to change its value
             let mut running_with_scissors = *menu_state.pause_sent.write().unwrap();
             if !running_with_scissors {
                 rt.block_on(async {
-                    c8.unpause().await;
+                    emu.chip8.unpause().await;
                 });
                 running_with_scissors = true;
             }
         }
+    }
 
-        canvas.window().gl_swap_window();
+    fn end_frame(&mut self) {
+        self.canvas.window().gl_swap_window();
+        std::thread::sleep(std::time::Duration::from_secs_f64(0.00001));
+    }
 
-        // Check fuse
-        if !fuse.alive() {
-            break 'running;
-        }
+    fn shutdown(&mut self) {
+        stop_recording(self.recording.take());
+        debug!("Exiting GUI Task");
+    }
+}
 
-        std::thread::sleep(std::time::Duration::from_secs_f64(0.00001));
+fn stop_recording(recording: Option<(Recorder, Instant)>) {
+    if let Some((rec, started)) = recording {
+        let ticks = (started.elapsed().as_secs_f64() * FRAME_RATE as f64) as u64;
+        match rec.finish(ticks) {
+            Ok(path) => info!("Saved recording {}", path.display()),
+            Err(e) => warn!("Unable to finish recording: {}", e),
+        }
     }
-    debug!("Exiting GUI Task");
 }
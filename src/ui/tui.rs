@@ -0,0 +1,322 @@
+// ui/tui.rs: terminal frontend, for running over SSH
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::io::{self, BufWriter, Stdout, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::{
+    cursor,
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal,
+};
+use log::{debug, warn};
+use tokio::runtime::Handle;
+
+use crate::display::{DisplaySettings, Persistence};
+use crate::emulator::Emulator;
+use crate::input::InputHandle;
+use crate::keymap::{Keymap, Keymaps};
+use crate::palette::{Palette, Rgb};
+use crate::rom_settings::RomSettingsStore;
+use crate::ui::{Frontend, UiOptions, FRAME};
+use crate::vram::Frame;
+
+// Most terminals only send key presses, repeated while the key is held.
+// A key counts as held until a repeat is overdue: the first repeat is slow to arrive,
+// the ones after it come quickly.
+const FIRST_REPEAT: Duration = Duration::from_millis(500);
+const REPEAT: Duration = Duration::from_millis(100);
+
+/// Raw mode on the alternate screen, put back the way it was on drop
+struct Terminal {
+    out: BufWriter<Stdout>,
+    // Whether the terminal reports key releases
+    releases: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut out = BufWriter::new(io::stdout());
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(
+                out,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        debug!("Terminal reports key releases: {}", releases);
+        Ok(Self { out, releases })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(
+            self.out,
+            ResetColor,
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Keypad keys held from the terminal, and when each one is let go of
+/// if the terminal can't say so itself
+#[derive(Default)]
+struct HeldKeys {
+    held: [bool; 16],
+    release_at: [Option<Instant>; 16],
+}
+
+impl HeldKeys {
+    /// Keys that changed: (key, pressed)
+    fn key_event(&mut self, key: u8, kind: KeyEventKind, releases: bool) -> Option<(u8, bool)> {
+        let k = key as usize;
+        let was_held = self.held[k];
+        match kind {
+            KeyEventKind::Release => {
+                self.held[k] = false;
+                self.release_at[k] = None;
+                was_held.then_some((key, false))
+            }
+            KeyEventKind::Press | KeyEventKind::Repeat => {
+                self.held[k] = true;
+                if !releases {
+                    let hold = if was_held { REPEAT } else { FIRST_REPEAT };
+                    self.release_at[k] = Some(Instant::now() + hold);
+                }
+                (!was_held).then_some((key, true))
+            }
+        }
+    }
+
+    /// Let go of keys whose repeats have stopped
+    fn expire(&mut self, now: Instant) -> Vec<u8> {
+        let mut released = Vec::new();
+        for k in 0..16 {
+            if self.release_at[k].is_some_and(|at| at <= now) {
+                self.held[k] = false;
+                self.release_at[k] = None;
+                released.push(k as u8);
+            }
+        }
+        released
+    }
+}
+
+/// Draws in the terminal, reads keys from it and rings its bell for the buzzer
+pub struct TuiFrontend {
+    term: Terminal,
+    keys: HeldKeys,
+    keymap: Keymap,
+    options: UiOptions,
+    display: DisplaySettings,
+    persistence: Persistence,
+    title: String,
+    next_frame: Instant,
+    // The terminal was cleared, the screen has to be drawn again
+    redraw: bool,
+    bell: bool,
+    // Set once the terminal stops working, ends the loop
+    failed: bool,
+}
+
+impl TuiFrontend {
+    pub fn new(mut options: UiOptions) -> io::Result<Self> {
+        let saved = RomSettingsStore::load().get(&options.rom_sha1, &options.rom_name);
+        let keymaps = Keymaps::load();
+        let keymap = keymaps
+            .get(&keymaps.layout_for(&saved, &options.rom_name))
+            .cloned()
+            .unwrap_or_else(Keymap::qwerty);
+        let display = saved.display.unwrap_or(options.display);
+        if let Some(palette) = saved.palette() {
+            options.palette = palette;
+        }
+        let title = if options.rom_name.is_empty() {
+            String::from("rusty_chips")
+        } else {
+            options.rom_name.clone()
+        };
+        Ok(Self {
+            term: Terminal::enter()?,
+            keys: HeldKeys::default(),
+            keymap,
+            options,
+            display,
+            persistence: Persistence::new(),
+            title,
+            next_frame: Instant::now(),
+            redraw: true,
+            bell: false,
+            failed: false,
+        })
+    }
+
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            warn!("Terminal error: {}", e);
+            self.failed = true;
+        }
+    }
+
+    /// Keys until the next frame is due, Ok(false) if the user quit
+    fn read_keys(&mut self, input: &InputHandle, rt: &Handle) -> io::Result<bool> {
+        while event::poll(self.next_frame.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(KeyEvent {
+                    code, modifiers, ..
+                }) if code == KeyCode::Esc
+                    || (code == KeyCode::Char('c')
+                        && modifiers.contains(KeyModifiers::CONTROL)) =>
+                {
+                    return Ok(false);
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char(c),
+                    kind,
+                    ..
+                }) => {
+                    let name = c.to_uppercase().to_string();
+                    let releases = self.term.releases;
+                    let change = self
+                        .keymap
+                        .lookup(&name)
+                        .and_then(|key| self.keys.key_event(key, kind, releases));
+                    match change {
+                        Some((key, true)) => rt.block_on(async { input.key_down(key).await }),
+                        Some((key, false)) => rt.block_on(async { input.key_up(key).await }),
+                        None => (),
+                    }
+                }
+                Event::Resize(..) => {
+                    queue!(
+                        self.term.out,
+                        ResetColor,
+                        terminal::Clear(terminal::ClearType::All)
+                    )?;
+                    self.redraw = true;
+                }
+                _ => (),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Frontend for TuiFrontend {
+    fn poll_input(&mut self, emu: &Emulator, rt: &Handle) -> bool {
+        let keep_going = match self.read_keys(&emu.input, rt) {
+            Ok(keep_going) => keep_going,
+            Err(e) => {
+                warn!("Terminal error: {}", e);
+                false
+            }
+        };
+        let now = Instant::now();
+        self.next_frame = (self.next_frame + FRAME).max(now);
+        for key in self.keys.expire(now) {
+            rt.block_on(async { emu.input.key_up(key).await });
+        }
+        keep_going && !self.failed
+    }
+
+    fn present(&mut self, frame: &Frame, changed: bool, _emu: &Emulator, _rt: &Handle) {
+        if changed || self.redraw || self.persistence.is_fading() {
+            self.redraw = false;
+            self.persistence.update(&frame.mem, self.display);
+            let result = draw(
+                &mut self.term.out,
+                &self.persistence,
+                &self.options.palette,
+                &self.title,
+            );
+            self.check(result);
+        }
+    }
+
+    /// A terminal bell each time the buzzer starts
+    fn drive_audio(&mut self, emu: &Emulator) {
+        let buzzing = emu.buzzer.is_on();
+        if buzzing && !self.bell && !self.options.audio_settings.muted {
+            let result = queue!(self.term.out, Print('\x07'));
+            self.check(result);
+        }
+        self.bell = buzzing;
+    }
+
+    fn end_frame(&mut self) {
+        let result = self.term.out.flush();
+        self.check(result);
+    }
+
+    fn shutdown(&mut self) {
+        debug!("Exiting TUI Task");
+    }
+}
+
+fn color([r, g, b]: Rgb) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+/// Two chip8 rows per line of text: the top pixel in the foreground of '▀',
+/// the bottom one in the background
+fn draw(
+    out: &mut impl Write,
+    persistence: &Persistence,
+    palette: &Palette,
+    title: &str,
+) -> io::Result<()> {
+    let (width, height) = persistence.size();
+    queue!(
+        out,
+        cursor::MoveTo(0, 0),
+        ResetColor,
+        Print(format!("{}  (Esc quits)", title))
+    )?;
+    for y in (0..height).step_by(2) {
+        queue!(out, cursor::MoveTo(0, (y / 2 + 1) as u16))?;
+        let mut last = None;
+        for x in 0..width {
+            let top = persistence.color(x, y, palette);
+            let bottom = if y + 1 < height {
+                persistence.color(x, y + 1, palette)
+            } else {
+                palette.background()
+            };
+            if last != Some((top, bottom)) {
+                queue!(
+                    out,
+                    SetForegroundColor(color(top)),
+                    SetBackgroundColor(color(bottom))
+                )?;
+                last = Some((top, bottom));
+            }
+            queue!(out, Print('▀'))?;
+        }
+    }
+    queue!(out, ResetColor)
+}
@@ -13,7 +13,13 @@
 
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fs;
+use std::path::PathBuf;
+
+use log::{debug, warn};
 use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
 pub fn test_roms() -> Vec<Vec<u8>> {
     let mut roms = Vec::new();
@@ -26,28 +32,119 @@ pub fn test_roms() -> Vec<Vec<u8>> {
     roms
 }
 
-fn input_to_hertz(input: &str) -> u128 {
+fn input_to_hertz(input: &str) -> Result<u128, String> {
+    let err = || format!("'{}' is not a speed, e.g. 500Hz or 1.76MHz", input);
     let re_num = Regex::new(r"\d+(\.\d+)?").unwrap();
     let num_range: (usize, usize) = {
-        let matches = re_num.find(input).unwrap();
+        let matches = re_num.find(input).ok_or_else(err)?;
         (matches.start(), matches.end())
     };
-    let number: f64 = input[num_range.0..num_range.1].trim().parse().unwrap();
+    let number: f64 = input[num_range.0..num_range.1]
+        .trim()
+        .parse()
+        .map_err(|_| err())?;
     let freq = input[num_range.1..].to_string().to_lowercase();
     let multiplier = match freq.as_str() {
         "ghz" => 1000 * 1000,
         "mhz" => 1000,
         "hz" => 1,
-        _ => panic!("Chip8 Frequency must end with GHz, MHz, or Hz"),
+        _ => {
+            return Err(String::from(
+                "Chip8 Frequency must end with GHz, MHz, or Hz",
+            ))
+        }
     };
     let frequency_in_hertz = number * (multiplier as f64);
-    frequency_in_hertz.floor() as u128
+    match frequency_in_hertz.floor() as u128 {
+        0 => Err(err()),
+        hertz => Ok(hertz),
+    }
 }
 
 fn hertz_to_seconds(hertz: u128) -> f64 {
     1f64 / (hertz as f64)
 }
 
+/// How fast the chip8 runs unless told otherwise
+pub const DEFAULT_SPEED: &str = "1.76Mhz";
+
+/// "500Hz", "1.76MHz" => seconds per instruction
+pub fn parse_speed(input: &str) -> Result<f64, String> {
+    input_to_hertz(input).map(hertz_to_seconds)
+}
+
 pub fn hz_to_secs(input: &str) -> f64 {
-    hertz_to_seconds(input_to_hertz(input))
+    parse_speed(input).unwrap()
+}
+
+/// Seconds per instruction => "500Hz", the other way around from `parse_speed`
+pub fn format_speed(secs: f64) -> String {
+    format!("{}Hz", (1.0 / secs).round())
+}
+
+/// $XDG_CONFIG_HOME/rusty_chips, falling back to ~/.config/rusty_chips
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()?.join(".config"),
+    };
+    Some(base.join("rusty_chips"))
+}
+
+/// `name` in the config directory. Missing files read as the default, so do
+/// malformed ones, after a warning.
+pub fn load_toml<T: DeserializeOwned + Default>(name: &str) -> T {
+    match config_dir().map(|d| fs::read_to_string(d.join(name))) {
+        Some(Ok(contents)) => parse_toml(name, &contents),
+        _ => T::default(),
+    }
+}
+
+/// The `contents` of the file `name`, the default if they're malformed
+pub fn parse_toml<T: DeserializeOwned + Default>(name: &str, contents: &str) -> T {
+    toml::from_str(contents).unwrap_or_else(|e| {
+        warn!("Ignoring malformed {}: {}", name, e);
+        T::default()
+    })
+}
+
+/// Write `value` to `name` in the config directory, creating the directory if need be
+pub fn save_toml<T: Serialize>(name: &str, value: &T) {
+    let Some(path) = config_dir().map(|d| d.join(name)) else {
+        warn!("No config directory, unable to save {}", name);
+        return;
+    };
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    match toml::to_string_pretty(value) {
+        Ok(contents) => match fs::write(&path, contents) {
+            Ok(_) => debug!("Saved {}", path.display()),
+            Err(e) => warn!("Unable to write {}: {}", path.display(), e),
+        },
+        Err(e) => warn!("Unable to serialize {}: {}", name, e),
+    }
+}
+
+/// File name of a ROM, used to key per game settings
+pub fn rom_name(path: &str) -> String {
+    PathBuf::from(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from)
+        .unwrap_or_else(|| String::from(path))
+}
+
+/// "1280x720" => (1280, 720)
+pub fn parse_size(input: &str) -> Result<(u32, u32), String> {
+    let err = || format!("'{}' is not a WIDTHxHEIGHT size", input);
+    let (w, h) = input
+        .to_lowercase()
+        .split_once('x')
+        .map(|(w, h)| (w.trim().parse(), h.trim().parse()))
+        .ok_or_else(err)?;
+    match (w, h) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(err()),
+    }
 }
@@ -14,12 +14,13 @@
 /// You should have received a copy of the GNU Affero General Public License
 /// along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 
 // TODO: Remove this allowance when SuperChip8 is ready
 #[allow(dead_code)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ScreenSize {
     L,
     S,
@@ -41,6 +42,29 @@ impl Memory {
     fn S() -> Self {
         Memory::s(Box::new([[false; 64]; 32]))
     }
+
+    /// An all off screen
+    pub fn blank(size: ScreenSize) -> Self {
+        match size {
+            ScreenSize::L => Memory::L(),
+            ScreenSize::S => Memory::S(),
+        }
+    }
+
+    pub fn screen_size(&self) -> ScreenSize {
+        match self {
+            Memory::l(_) => ScreenSize::L,
+            Memory::s(_) => ScreenSize::S,
+        }
+    }
+
+    /// (width, height) in pixels
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            Memory::l(_) => (128, 64),
+            Memory::s(_) => (64, 32),
+        }
+    }
 }
 
 impl Index<(usize, usize)> for Memory {
@@ -63,49 +87,121 @@ impl IndexMut<(usize, usize)> for Memory {
     }
 }
 
+/// A published screen, shared rather than copied between readers
+#[derive(Clone, Debug)]
+pub struct Frame {
+    // Counts up with every change to the screen
+    pub number: u64,
+    pub mem: Arc<Memory>,
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct VRAM {
     width: usize,
     height: usize,
-    mem: Memory,
+    // Copied on write only while a reader still holds the last published frame
+    mem: Arc<Memory>,
+    frame_number: u64,
+    dirty: bool,
+    frames: watch::Sender<Frame>,
     receiver: mpsc::Receiver<VRAMMessage>,
 }
 
 impl VRAM {
-    fn new_large(receiver: mpsc::Receiver<VRAMMessage>) -> Self {
+    fn new(
+        mem: Memory,
+        frames: watch::Sender<Frame>,
+        receiver: mpsc::Receiver<VRAMMessage>,
+    ) -> Self {
+        let (width, height) = mem.size();
         VRAM {
-            width: 128,
-            height: 64,
-            mem: Memory::L(),
-            receiver,
-        }
-    }
-
-    fn new_small(receiver: mpsc::Receiver<VRAMMessage>) -> Self {
-        VRAM {
-            width: 64,
-            height: 32,
-            mem: Memory::S(),
+            width,
+            height,
+            mem: Arc::new(mem),
+            frame_number: 0,
+            dirty: false,
+            frames,
             receiver,
         }
     }
 
     async fn handle_message(&mut self, msg: VRAMMessage) {
         match msg {
-            VRAMMessage::Get { respond_to } => respond_to.send(self.mem.clone()).await.unwrap(),
             VRAMMessage::GetPixel { x, y, respond_to } => {
                 respond_to.send(self[(x, y)]).await.unwrap()
             }
-            VRAMMessage::SetPixel { x, y, value } => self[(x, y)] = value,
+            VRAMMessage::SetPixel { x, y, value } => {
+                self[(x, y)] = value;
+                self.dirty = true;
+            }
+            VRAMMessage::Draw {
+                x,
+                y,
+                sprite,
+                wrap,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.draw(x, y, &sprite, wrap));
+            }
+            VRAMMessage::Screen { respond_to } => {
+                let _ = respond_to.send(Memory::clone(&self.mem));
+            }
+            VRAMMessage::Load(mem) => {
+                self.width = mem.size().0;
+                self.height = mem.size().1;
+                self.mem = Arc::new(mem);
+                self.dirty = true;
+            }
             VRAMMessage::Clear => {
                 for y in 0..self.height {
                     for x in 0..self.width {
                         self[(x, y)] = false
                     }
                 }
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// XOR `sprite` onto the screen, one byte per row, clipped at the edges.
+    /// true if it turned any pixel off.
+    fn draw(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        let (x, y) = (x % self.width, y % self.height);
+        let mut collision = false;
+        for (row, bits) in sprite.iter().enumerate() {
+            let py = y + row;
+            if py >= self.height && !wrap {
+                break;
+            }
+            let py = py % self.height;
+            for col in 0..8 {
+                let px = x + col;
+                if px >= self.width && !wrap {
+                    break;
+                }
+                let px = px % self.width;
+                if bits & (0x80 >> col) != 0 {
+                    let pixel = &mut self[(px, py)];
+                    collision |= *pixel;
+                    *pixel = !*pixel;
+                    self.dirty = true;
+                }
             }
         }
+        collision
+    }
+
+    /// Hand the screen to subscribers if it changed since it was last published
+    fn publish(&mut self) {
+        if self.dirty {
+            self.dirty = false;
+            self.frame_number += 1;
+            self.frames.send_replace(Frame {
+                number: self.frame_number,
+                mem: self.mem.clone(),
+            });
+        }
     }
 }
 
@@ -119,21 +215,23 @@ impl Index<(usize, usize)> for VRAM {
 
 impl IndexMut<(usize, usize)> for VRAM {
     fn index_mut(&mut self, pos: (usize, usize)) -> &mut Self::Output {
-        &mut self.mem[pos]
+        &mut Arc::make_mut(&mut self.mem)[pos]
     }
 }
 
 pub async fn vram_runner(mut video: VRAM) {
     while let Some(msg) = video.receiver.recv().await {
-        video.handle_message(msg).await
+        video.handle_message(msg).await;
+        // Publish once the queue is drained, not once per message
+        while let Ok(msg) = video.receiver.try_recv() {
+            video.handle_message(msg).await;
+        }
+        video.publish();
     }
 }
 
 #[derive(Debug)]
 pub enum VRAMMessage {
-    Get {
-        respond_to: mpsc::Sender<Memory>,
-    },
     GetPixel {
         x: usize,
         y: usize,
@@ -144,26 +242,43 @@ pub enum VRAMMessage {
         y: usize,
         value: bool,
     },
+    // A whole sprite, so the screen is published once it is all drawn
+    Draw {
+        x: usize,
+        y: usize,
+        sprite: Vec<u8>,
+        // Wrap around the edges instead of clipping
+        wrap: bool,
+        respond_to: oneshot::Sender<bool>,
+    },
     Clear,
+    Screen {
+        respond_to: oneshot::Sender<Memory>,
+    },
+    Load(Memory),
 }
 
 #[derive(Clone, Debug)]
 pub struct VRAMHandle {
     sender: mpsc::Sender<VRAMMessage>,
+    frames: watch::Receiver<Frame>,
     screen_size: ScreenSize,
 }
 
 impl VRAMHandle {
     pub fn new(screen_size: ScreenSize) -> Self {
         let (sender, receiver) = mpsc::channel(10);
-        let vram = match screen_size {
-            ScreenSize::L => VRAM::new_large(receiver),
-            ScreenSize::S => VRAM::new_small(receiver),
-        };
+        let mem = Memory::blank(screen_size);
+        let (frame_sender, frames) = watch::channel(Frame {
+            number: 0,
+            mem: Arc::new(mem.clone()),
+        });
+        let vram = VRAM::new(mem, frame_sender, receiver);
         tokio::spawn(vram_runner(vram));
 
         Self {
             sender,
+            frames,
             screen_size,
         }
     }
@@ -175,17 +290,14 @@ impl VRAMHandle {
         }
     }
 
-    pub async fn get(&self) -> Memory {
-        let (send, mut recv) = mpsc::channel(10);
-        let msg = VRAMMessage::Get { respond_to: send };
-        let _ = self.sender.send(msg).await;
-        loop {
-            let m = recv.recv().await;
-            return match m {
-                Some(mem) => mem,
-                _ => continue,
-            };
-        }
+    /// The latest published frame
+    pub fn frame(&self) -> Frame {
+        self.frames.borrow().clone()
+    }
+
+    /// A receiver that is woken each time a changed frame is published
+    pub fn subscribe(&self) -> watch::Receiver<Frame> {
+        self.frames.clone()
     }
 
     pub async fn get_pixel(&self, x: usize, y: usize) -> bool {
@@ -204,8 +316,77 @@ impl VRAMHandle {
         let _ = self.sender.send(msg).await;
     }
 
+    /// XOR a sprite onto the screen at (x, y), true if that turned any pixel off
+    pub async fn draw(&self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = VRAMMessage::Draw {
+            x,
+            y,
+            sprite: sprite.to_vec(),
+            wrap,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+        recv.await.unwrap_or(false)
+    }
+
     pub async fn clear_screen(&self) {
         let msg = VRAMMessage::Clear;
         let _ = self.sender.send(msg).await;
     }
+
+    /// The screen with every change sent so far applied
+    pub async fn screen(&self) -> Memory {
+        let (send, recv) = oneshot::channel();
+        let msg = VRAMMessage::Screen { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.unwrap()
+    }
+
+    /// Replace the whole screen
+    pub async fn load_screen(&self, mem: Memory) {
+        let msg = VRAMMessage::Load(mem);
+        let _ = self.sender.send(msg).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sprite_is_published_once() {
+        let video = VRAMHandle::new(ScreenSize::S);
+        video.clear_screen().await;
+        video.screen().await;
+        assert_eq!(video.frame().number, 1);
+        // 14 pixels
+        assert!(!video.draw(10, 5, &[0xFF, 0x3F], false).await);
+        let frame = video.frame();
+        assert_eq!(frame.number, 2);
+        assert!(frame.mem[(10, 5)] && frame.mem[(17, 5)] && frame.mem[(12, 6)]);
+        assert!(!frame.mem[(11, 6)]);
+    }
+
+    #[tokio::test]
+    async fn draw_collides_and_clips() {
+        let video = VRAMHandle::new(ScreenSize::S);
+        assert!(!video.draw(60, 31, &[0xFF, 0xFF], false).await);
+        let screen = video.screen().await;
+        assert!(screen[(63, 31)] && !screen[(0, 31)] && !screen[(60, 0)]);
+        assert!(video.draw(60, 31, &[0x80], false).await);
+        assert!(!video.screen().await[(60, 31)]);
+        // Start positions wrap
+        assert!(!video.draw(64 + 2, 32 + 3, &[0x80], false).await);
+        assert!(video.screen().await[(2, 3)]);
+    }
+
+    #[tokio::test]
+    async fn draw_can_wrap() {
+        let video = VRAMHandle::new(ScreenSize::S);
+        assert!(!video.draw(60, 31, &[0xFF, 0xFF], true).await);
+        let screen = video.screen().await;
+        assert!(screen[(63, 31)] && screen[(0, 31)] && screen[(3, 31)]);
+        assert!(screen[(60, 0)] && screen[(3, 0)] && !screen[(4, 0)]);
+    }
 }
@@ -0,0 +1,270 @@
+// watch.rs: reload the ROM whenever its file changes, for homebrew development
+// Copyright (C) 2023 Justin Noah <justinnoah+rusty_chips@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use tokio::time;
+
+use crate::chip8::{Chip8Handle, State};
+use crate::fuse::FuseHandle;
+use crate::rom;
+
+// How often the ROM's modification time is checked
+const POLL: Duration = Duration::from_millis(250);
+const PROGRAM_START: usize = 0x200;
+
+/// Watches a ROM file and reloads the emulator with it whenever it's written to
+struct RomWatcher {
+    path: PathBuf,
+    // The file whose modification time is checked, the zip for pack.zip/GAME.ch8
+    file: PathBuf,
+    // Saved state to restore instead of resetting, see --watch-state
+    state_file: Option<PathBuf>,
+    // The machine before it ran anything, what a reload resets to
+    power_on: State,
+    // The file as it was last loaded
+    loaded: Option<Stamp>,
+    // A change seen on the last check, loaded once the file stops changing
+    pending: Option<Stamp>,
+    chip8: Chip8Handle,
+    fuse: FuseHandle,
+}
+
+/// Start watching `path`. `power_on` is the emulator's state before the ROM started,
+/// see `Chip8Handle::save_state`. Must be called from within a tokio runtime.
+pub fn spawn(
+    path: PathBuf,
+    state_file: Option<PathBuf>,
+    power_on: State,
+    chip8: Chip8Handle,
+    fuse: FuseHandle,
+) {
+    let file = path
+        .ancestors()
+        .find(|a| a.is_file())
+        .unwrap_or(&path)
+        .to_path_buf();
+    let watcher = RomWatcher {
+        loaded: stamp(&file),
+        pending: None,
+        file,
+        path,
+        state_file,
+        power_on,
+        chip8,
+        fuse,
+    };
+    info!("Watching {} for changes", watcher.path.display());
+    tokio::spawn(run_watcher(watcher));
+}
+
+/// A file's modification time and size
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+async fn run_watcher(mut watcher: RomWatcher) {
+    let mut interval = time::interval(POLL);
+    while watcher.fuse.alive() {
+        interval.tick().await;
+        let stamp = stamp(&watcher.file);
+        if stamp.is_none() || stamp == watcher.loaded {
+            watcher.pending = None;
+            continue;
+        }
+        // Still being written, wait until it's the same for a whole poll
+        if stamp != watcher.pending {
+            watcher.pending = stamp;
+            continue;
+        }
+        (watcher.loaded, watcher.pending) = (stamp, None);
+        match rom::read(&watcher.path) {
+            // Files are often emptied before they're written
+            Ok(rom) if rom.bytes.is_empty() => {
+                warn!("{} is empty, not reloading", watcher.path.display())
+            }
+            Ok(rom) => watcher.reload(&rom.bytes).await,
+            Err(e) => warn!("Unable to reload {}: {}", watcher.path.display(), e),
+        }
+    }
+}
+
+impl RomWatcher {
+    async fn reload(&self, rom: &[u8]) {
+        let mut state = self.saved_state().unwrap_or_else(|| self.power_on.clone());
+        let end = PROGRAM_START + rom.len();
+        state.memory[PROGRAM_START..end].copy_from_slice(rom);
+        // Nothing of the old program is left past the end of the new one
+        state.memory[end..].fill(0);
+        state.running = true;
+        self.chip8.load_state(state).await;
+        info!("Reloaded {}", self.path.display());
+    }
+
+    /// The state in --watch-state, with the old program still in it
+    fn saved_state(&self) -> Option<State> {
+        let path = self.state_file.as_ref()?;
+        match fs::read(path) {
+            Ok(bytes) => State::from_bytes(&bytes).or_else(|| {
+                warn!(
+                    "{} isn't a rusty_chips save state, resetting",
+                    path.display()
+                );
+                None
+            }),
+            // Not saved yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Unable to read {}, resetting: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+    use std::io::{Cursor, Write};
+    use std::time::UNIX_EPOCH;
+    use zip::write::{FileOptions, ZipWriter};
+
+    // V1 = 7, then loop at 0x202, with two bytes of data after
+    const OLD: [u8; 6] = [0x61, 0x07, 0x12, 0x02, 0xAB, 0xCD];
+    // V0 = 42, then loop at 0x202
+    const NEW: [u8; 4] = [0x60, 0x2A, 0x12, 0x02];
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_chips_watch_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write `bytes` to `path`, modified `secs` after the epoch
+    fn write(path: &Path, bytes: &[u8], secs: u64) {
+        fs::write(path, bytes).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn zip(name: &str, bytes: &[u8]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(bytes).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Run OLD while watching `path`, as main does
+    async fn start(path: &Path, state_file: Option<PathBuf>) -> Emulator {
+        let emu = Emulator::start(1.0 / 1000.0, Some(OLD.to_vec()));
+        emu.watch(path.to_path_buf(), state_file).await;
+        emu.chip8.unpause().await;
+        time::sleep(POLL).await;
+        emu
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_from_power_on() {
+        let dir = temp_dir("power_on");
+        let path = dir.join("game.ch8");
+        write(&path, &OLD, 1);
+        let emu = start(&path, None).await;
+        assert_eq!(emu.chip8.save_state().await.registers[1], 7);
+
+        write(&path, &NEW, 2);
+        time::sleep(POLL * 3).await;
+        let state = emu.chip8.save_state().await;
+        assert_eq!(state.memory[0x200..0x206], [0x60, 0x2A, 0x12, 0x02, 0, 0]);
+        assert_eq!(state.registers[0], 42);
+        assert_eq!(state.registers[1], 0);
+        assert_eq!(state.pc, 0x202);
+        emu.fuse.blow();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_into_the_saved_state() {
+        let dir = temp_dir("state");
+        let (path, state_file) = (dir.join("game.ch8"), dir.join("state.bin"));
+        write(&path, &OLD, 1);
+        let emu = start(&path, Some(state_file.clone())).await;
+        fs::write(&state_file, emu.chip8.save_state().await.to_bytes()).unwrap();
+
+        write(&path, &NEW, 2);
+        time::sleep(POLL * 3).await;
+        let state = emu.chip8.save_state().await;
+        // Carried on from the saved loop at 0x202, so V0 = 42 never ran
+        assert_eq!(state.memory[0x200..0x206], [0x60, 0x2A, 0x12, 0x02, 0, 0]);
+        assert_eq!(state.registers[0], 0);
+        assert_eq!(state.registers[1], 7);
+        assert_eq!(state.pc, 0x202);
+        emu.fuse.blow();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_out_of_a_zip() {
+        let dir = temp_dir("zip");
+        let pack = dir.join("pack.zip");
+        write(&pack, &zip("GAME.ch8", &OLD), 1);
+        let emu = start(&pack.join("GAME.ch8"), None).await;
+
+        write(&pack, &zip("GAME.ch8", &NEW), 2);
+        time::sleep(POLL * 3).await;
+        assert_eq!(emu.chip8.save_state().await.registers[0], 42);
+        emu.fuse.blow();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unfinished_files_keep_the_old_rom() {
+        let dir = temp_dir("unfinished");
+        let path = dir.join("game.ch8");
+        write(&path, &OLD, 1);
+        let emu = start(&path, None).await;
+
+        // Emptied, then not a ROM at all
+        write(&path, &[], 2);
+        time::sleep(POLL * 3).await;
+        write(&path, &[0; 4096], 3);
+        time::sleep(POLL * 3).await;
+        let state = emu.chip8.save_state().await;
+        assert_eq!(state.memory[0x200..0x206], OLD);
+        assert_eq!(state.registers[1], 7);
+
+        // Half written, then finished before the next check
+        write(&path, &NEW[..2], 4);
+        time::sleep(POLL + POLL / 2).await;
+        write(&path, &NEW, 5);
+        time::sleep(POLL).await;
+        assert_eq!(emu.chip8.save_state().await.memory[0x200..0x202], OLD[..2]);
+        time::sleep(POLL * 2).await;
+        let state = emu.chip8.save_state().await;
+        assert_eq!(state.memory[0x200..0x204], NEW);
+        assert_eq!(state.registers[0], 42);
+        emu.fuse.blow();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}